@@ -0,0 +1,36 @@
+// Seccomp audit mode: the syscalls that the profile would refuse are notified
+// to the supervisor, that records them and lets them continue
+use crate::errors::Errcode;
+use crate::seccomp::SeccompProfile;
+
+use std::fs::write;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub syscall: String,
+    pub args: [u64; 6],
+}
+
+// Write the profile that would allow the audited syscalls, to the output file
+// or to stderr
+pub fn write_suggestion(profile: &SeccompProfile, records: &[AuditRecord], output: &Option<PathBuf>) -> Result<(), Errcode> {
+    log::info!("Seccomp audit recorded {} refused syscalls", records.len());
+    let suggested = profile.suggest(records);
+    let contents = match serde_json::to_string_pretty(&suggested) {
+        Ok(contents) => contents,
+        Err(e) => return Err(Errcode::ProfileError(format!("Can not serialize suggested seccomp profile: {}", e))),
+    };
+
+    match output {
+        Some(path) => {
+            if let Err(e) = write(path, contents + "\n") {
+                log::error!("Can not write suggested seccomp profile to {}: {}", path.display(), e);
+                return Err(Errcode::IoError("Can not write suggested seccomp profile".to_string(), e));
+            }
+            log::info!("Suggested seccomp profile written to {}", path.display());
+        }
+        None => eprintln!("{}", contents),
+    }
+    Ok(())
+}
@@ -1,38 +1,100 @@
-use capctl::caps::{Cap, FullCapState};
+use capctl::caps::{ambient, bounding, Cap, CapSet, CapState};
+use capctl::prctl;
 use crate::errors::Errcode;
-const CAPABILITIES_DROP: [Cap; 21] = [
-    Cap::AUDIT_CONTROL,
-    Cap::AUDIT_READ,
-    Cap::AUDIT_WRITE,
-    Cap::BLOCK_SUSPEND,
-    Cap::DAC_READ_SEARCH,
-    Cap::DAC_OVERRIDE,
-    Cap::FSETID,
-    Cap::IPC_LOCK,
-    Cap::MAC_ADMIN,
-    Cap::MAC_OVERRIDE,
-    Cap::MKNOD,
-    Cap::SETFCAP,
-    Cap::SYSLOG,
-    Cap::SYS_ADMIN,
-    Cap::SYS_BOOT,
-    Cap::SYS_MODULE,
-    Cap::SYS_NICE,
-    Cap::SYS_RAWIO,
-    Cap::SYS_RESOURCE,
-    Cap::SYS_TIME,
-    Cap::WAKE_ALARM
-];
-
-pub fn setcapabilities() -> Result<(), Errcode> {
-    log::debug!("Clearing unwanted capabilities");
-    // if let Ok(mut caps) = FullCapState::get_current() {
-    match FullCapState::get_current() {
-        Ok(mut caps) => {
-            caps.bounding.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-            caps.inheritable.drop_all(CAPABILITIES_DROP.iter().map(|&cap| cap));
-            Ok(())
-        },
-        Err(e) => Err(Errcode::Capabilities(format!("Error while trying to get an handler to thread capabilities: {e}"))),
+
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+// Capabilities that the jail keeps, every other one is dropped
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapabilityPolicy {
+    // Names such as CAP_NET_BIND_SERVICE
+    pub keep: Vec<String>,
+}
+
+impl CapabilityPolicy {
+    pub fn keep_set(&self) -> Result<CapSet, Errcode> {
+        self.keep.iter().map(|name| {
+            Cap::from_str(name).map_err(|_| Errcode::ProfileError(format!("Unknown capability {}", name)))
+        }).collect()
+    }
+}
+
+pub fn setcapabilities(policy: &CapabilityPolicy) -> Result<(), Errcode> {
+    log::debug!("Dropping every capability but {:?}", policy.keep);
+    let keep = policy.keep_set()?;
+
+    // The bounding set limits what a later execve can gain, root included
+    for cap in Cap::iter() {
+        // Capabilities that the kernel does not know are read as None
+        if !keep.has(cap) && bounding::read(cap) == Some(true) {
+            if let Err(e) = bounding::drop(cap) {
+                return Err(Errcode::SysError(format!("Can not drop {:?} from the bounding set", cap), Errno::from_i32(e.code())));
+            }
+        }
+    }
+    if let Err(e) = ambient::clear() {
+        return Err(Errcode::SysError("Can not clear the ambient capabilities".to_string(), Errno::from_i32(e.code())));
+    }
+
+    let mut caps = match CapState::get_current() {
+        Ok(caps) => caps,
+        Err(e) => return Err(Errcode::SysError("Error while trying to get an handler to thread capabilities".to_string(), Errno::from_i32(e.code()))),
+    };
+    caps.permitted = caps.permitted.iter().filter(|cap| keep.has(*cap)).collect();
+    caps.effective.clear();
+    caps.inheritable.clear();
+    if let Err(e) = caps.set_current() {
+        return Err(Errcode::SysError("Can not set thread capabilities".to_string(), Errno::from_i32(e.code())));
+    }
+
+    // Neither setuid binaries nor file capabilities can give them back
+    if let Err(e) = prctl::set_no_new_privs() {
+        return Err(Errcode::SysError("Can not set no_new_privs".to_string(), Errno::from_i32(e.code())));
+    }
+
+    verify_capabilities(keep)
+}
+
+// Check the state seen by the kernel, so that a drop that silently did not
+// happen stops the jail
+fn verify_capabilities(keep: CapSet) -> Result<(), Errcode> {
+    let status = match read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(e) => return Err(Errcode::IoError("Can not read /proc/self/status".to_string(), e)),
+    };
+
+    let mut checked = 0;
+    for line in status.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key, value.trim()),
+            None => continue,
+        };
+        let valid = match key {
+            "CapInh" | "CapEff" | "CapAmb" => parse_capset(value)?.is_empty(),
+            "CapPrm" | "CapBnd" => parse_capset(value)?.iter().all(|cap| keep.has(cap)),
+            "NoNewPrivs" => value == "1",
+            _ => continue,
+        };
+        if !valid {
+            log::error!("Capabilities were not dropped, {} is {}", key, value);
+            return Err(Errcode::Capabilities(format!("{} is {} after dropping capabilities", key, value)));
+        }
+        checked += 1;
+    }
+
+    if checked != 6 {
+        return Err(Errcode::Capabilities("Can not find the capability sets in /proc/self/status".to_string()));
+    }
+    Ok(())
+}
+
+fn parse_capset(value: &str) -> Result<CapSet, Errcode> {
+    match u64::from_str_radix(value, 16) {
+        Ok(bits) => Ok(CapSet::from_bitmask_truncate(bits)),
+        Err(e) => Err(Errcode::Capabilities(format!("Invalid capability set {} in /proc/self/status: {e}", value))),
     }
 }
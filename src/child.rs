@@ -1,18 +1,26 @@
 use crate::capabilities::setcapabilities;
 use crate::config::ContainerOpts;
-use crate::errors::{Errcode, exit_with_errcode};
+use crate::errors::{Errcode, SetupStage, exit_with_errcode, in_stage};
 use crate::hostname::set_container_hostname;
-use crate::mountpoint::remount_root;
-use crate::namespaces::{mount_netns, split_namespace, userns};
+use crate::init::start_init;
+use crate::ipc::{recv_message, send_fd, send_message, SetupMessage};
+use crate::landlock::setlandlock;
+use crate::mountpoint::{remount_root, set_container_mountpoint, set_host_root, set_minimal_root};
+use crate::namespaces::{mount_netns, split_namespace};
 use crate::net::prepare_net;
-use crate::nftables::test_apply_ruleset;
+use crate::nftables::{delete_ruleset, test_apply_ruleset};
+use crate::pty::set_controlling_terminal;
+use crate::rollback::Rollback;
 use crate::syscalls::setsyscalls;
 
-use nix::unistd::{Pid, execve};
-use nix::sched::clone;
-use nix::sys::signal::Signal;
+use nix::unistd::{close, Pid, execve};
+use nix::sched::{clone, unshare};
+use nix::sys::signal::{pthread_sigmask, SigmaskHow, Signal};
 use nix::sched::CloneFlags;
 use std::ffi::CString;
+use std::fs::remove_dir_all;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
 
 const STACK_SIZE: usize = 1024 * 1024;
 
@@ -22,7 +30,6 @@ pub fn generate_child_process(config: &mut ContainerOpts) -> Result<Pid, Errcode
     flags.insert(CloneFlags::CLONE_NEWNS);
     flags.insert(CloneFlags::CLONE_NEWNET);
     flags.insert(CloneFlags::CLONE_NEWUSER);
-    flags.insert(CloneFlags::CLONE_NEWCGROUP);
     flags.insert(CloneFlags::CLONE_NEWPID);
     flags.insert(CloneFlags::CLONE_NEWIPC);
     flags.insert(CloneFlags::CLONE_NEWUTS);
@@ -37,28 +44,82 @@ pub fn generate_child_process(config: &mut ContainerOpts) -> Result<Pid, Errcode
             Some(Signal::SIGCHLD as i32)
             ) {
             Ok(pid) => Ok(pid),
-            Err(e) => Err(Errcode::ChildProcessError(format!("Error while trying to spawn child thread with clone: {e}"))),
+            Err(e) => Err(Errcode::SysError("Error while trying to spawn child thread with clone".to_string(), e)),
         }
     }
 }
 
 fn child(config: &mut ContainerOpts) -> isize {
-    match setup_container_configurations(config) {
-        Ok(()) => log::info!("Container setup successfully!"),
-        Err(e) => {
-            log::error!("Error while configuring container: {:?}", e);
+    // O_CLOEXEC only drops it at execve, which the init never does: the
+    // foreground orjail would wait for the end of the jail
+    if let Some(ready_fd) = config.ready_fd.take() {
+        let _ = close(ready_fd);
+    }
+    if let Some(mask) = config.sigmask.as_ref() {
+        if let Err(e) = pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(mask), None) {
+            log::error!("Can not restore the signal mask: {}", e);
             return -1;
         }
     }
+    // Until the parent has written the uid/gid maps and slirp4netns runs,
+    // nothing can be set up
+    if let Err(e) = wait_network(config.fd) {
+        log::error!("Error while waiting for the parent: {:?}", e);
+        return -1;
+    }
 
-    // TODO clean socket conf
-    log::info!("Starting container with command: {} and args: {:?}", config.path.to_str().unwrap(), config.argv);
-    // Switch to target network namespace afteer the configuration is done
-    if let Err(e) = split_namespace(&config.namespace) {
+    // Until the setup is done, a failure undoes the previous steps before it
+    // is reported
+    let mut rollback = Rollback::new();
+    if let Err(e) = setup_jail(config, &mut rollback) {
+        rollback.unwind();
+        return report_failure(config.fd, e);
+    }
+
+    // The init stays out of the syscall filter, which allowlist profiles
+    // would make too strict for it, but not of the restrictions above
+    if config.init {
+        match start_init(config.tty, config.fd) {
+            // The jail ran, the parent cleans it up
+            Ok(Some(retcode)) => {
+                rollback.commit();
+                return retcode;
+            }
+            Ok(None) => {},
+            Err(e) => {
+                rollback.unwind();
+                return report_failure(config.fd, in_stage(SetupStage::Init)(e));
+            }
+        }
+    }
+
+    // Allowlist profiles do not permit the namespace switch above, so the
+    // syscall filter is loaded as the very last step
+    let notify_fd = if config.disable_syscall {
+        None
+    } else {
+        match setsyscalls(&config.profile.seccomp, &config.seccomp_profile, &config.profile.supervisor, config.seccomp_audit, config.fd) {
+            Ok(notify_fd) => notify_fd,
+            Err(e) => {
+                rollback.unwind();
+                return report_failure(config.fd, in_stage(SetupStage::Seccomp)(e));
+            }
+        }
+    };
+    rollback.commit();
+
+    if let Err(e) = send_message(config.fd, &SetupMessage::SetupDone) {
         exit_with_errcode(e);
     }
+    // Nothing can run before the parent listens to the notification fd
+    if let Some(notify_fd) = notify_fd {
+        if let Err(e) = send_fd(config.fd, notify_fd) {
+            exit_with_errcode(e);
+        }
+    }
+    log::info!("Starting container with command: {} and args: {:?}", config.path.to_string_lossy(), config.argv);
 
-    let retcode = match execve::<CString, CString>(&config.path, &config.argv, &[]) {
+    let retcode = match execve::<CString, CString>(&config.path, &config.argv, &config.env) {
         Ok(_) => 0,
         Err(e) => {
             log::error!("Error while trying to perform execve {:?}", e);
@@ -68,39 +129,98 @@ fn child(config: &mut ContainerOpts) -> isize {
     retcode
 }
 
-fn setup_container_configurations(config: &mut ContainerOpts) -> Result<(), Errcode> {
-    let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
-    set_container_hostname(&config.hostname)?;
-    // TODO by looking at the rnetlink implementation maybe we do not need this step
-    if let Err(e) = mount_netns(&config.hostname) {
-        log::error!("{:?}", e);
-        // TODO return error and check status for each call
-    };
-    if let Err(e) = userns(config.real_uid, config.real_gid, config.uid) {
-        log::error!("Error in namespace configuration: {:?}", e);
+fn wait_network(socket: RawFd) -> Result<(), Errcode> {
+    send_message(socket, &SetupMessage::NamespacesCreated)?;
+    match recv_message(socket)? {
+        SetupMessage::NetworkReady => Ok(()),
+        message => Err(Errcode::SocketError(format!("Unexpected message from the parent: {:?}", message))),
     }
+}
 
-    remount_root()?;
-    // TODO maybe change name to the network namespace and make these parameters configurable
-    let (_veth_idx, _veth_2_idx) = rt.block_on(
-        prepare_net(&config.namespace, "10.40.50.10", "10.40.50.20", 24)).expect("Failed to prepare network");
-    // TODO namespace configuration and clean!
-    // TODO all this should be configurable
-    test_apply_ruleset();
+// The parent logs the error and exits with the code of its stage, it also
+// kills what the child left running
+fn report_failure(socket: RawFd, e: Errcode) -> isize {
+    log::debug!("Reporting setup error to the parent: {}", e);
+    let retcode = e.get_retcode() as isize;
+    if let Err(e) = send_message(socket, &SetupMessage::Failed(e)) {
+        log::error!("Can not report the setup error to the parent: {}", e);
+    }
+    retcode
+}
 
-    // Last step drop capabilities and limit syscalls
+fn setup_jail(config: &mut ContainerOpts, rollback: &mut Rollback) -> Result<(), Errcode> {
+    // The parent moved the child in the cgroup of the jail, if any, which
+    // becomes the root of its cgroup namespace
+    if let Err(e) = unshare(CloneFlags::CLONE_NEWCGROUP) {
+        return Err(in_stage(SetupStage::Resources)(Errcode::SysError("Can not unshare the cgroup namespace".to_string(), e)));
+    }
+    setup_container_configurations(config, rollback)?;
+
+    // Switch to target network namespace afteer the configuration is done
+    split_namespace(&config.namespace).map_err(in_stage(SetupStage::Network))?;
+
+    // The network namespace and the TOR binary are reached through the host
+    // root, so the root is pivoted once they are set up
+    match (&config.mount_dir, &config.root) {
+        (Some(mount_dir), Some(root)) => set_container_mountpoint(mount_dir, root, &config.mounts, &config.profile.devices),
+        (None, Some(root)) if config.minimal_root => set_minimal_root(root, &config.mounts, &config.profile.devices),
+        _ => set_host_root(&config.mounts, &config.profile.devices),
+    }.map_err(in_stage(SetupStage::Mounts))?;
+
+    if let Some(tty) = config.tty {
+        set_controlling_terminal(tty).map_err(in_stage(SetupStage::Terminal))?;
+    }
+
+    // Joining the namespace needs CAP_SYS_ADMIN, so capabilities are dropped
+    // afterwards. no_new_privs is set as well, which the filter below needs.
     if !config.disable_capabilities {
-        setcapabilities()?;
+        setcapabilities(&config.profile.capabilities).map_err(in_stage(SetupStage::Capabilities))?;
     }
 
-    if !config.disable_syscall {
-        setsyscalls()?;
+    // Tor and the namespace switch above are not restricted by Landlock
+    if config.profile.landlock.enabled {
+        setlandlock(&config.profile.landlock, config.real_uid, &config.profile.devices.allowed).map_err(in_stage(SetupStage::Landlock))?;
     }
+    Ok(())
+}
 
+// Only the steps that leave something on the host, or in the network
+// namespace of the jail, have an undo action: the others are confined to the
+// namespaces of the child and disappear with it
+fn setup_container_configurations(config: &mut ContainerOpts, rollback: &mut Rollback) -> Result<(), Errcode> {
+    // The child is a clone of a parent that may run a runtime of its own, it
+    // only needs one on its thread for the netlink requests
+    let rt = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(rt) => rt,
+        Err(e) => return Err(in_stage(SetupStage::Network)(Errcode::IoError("Failed to create Tokio runtime".to_string(), e))),
+    };
+    set_container_hostname(&config.hostname).map_err(in_stage(SetupStage::Hostname))?;
+    // TODO by looking at the rnetlink implementation maybe we do not need this step
+    mount_netns(&config.hostname).map_err(in_stage(SetupStage::Mounts))?;
+    let netns_dir = PathBuf::from(format!("/tmp/{}", config.hostname));
+    rollback.push("network namespace directories", move || match remove_dir_all(&netns_dir) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Errcode::IoError(format!("Can not remove {}", netns_dir.display()), e)),
+    });
+
+    remount_root().map_err(in_stage(SetupStage::Mounts))?;
+    // TODO maybe change name to the network namespace and make these parameters configurable
+    let (_veth_idx, _veth_2_idx) = rt.block_on(
+        prepare_net(&config.namespace, "10.40.50.10", "10.40.50.20", 24, rollback)).map_err(in_stage(SetupStage::Network))?;
+    // TODO namespace configuration and clean!
+    // TODO all this should be configurable
+    test_apply_ruleset().map_err(in_stage(SetupStage::Nftables))?;
+    rollback.push("nftables ruleset", delete_ruleset);
 
     // Last step run TOR from the container
-    config.spawn_tor();
+    config.spawn_tor().map_err(in_stage(SetupStage::Tor))?;
+    let tor = config.tor_process.clone();
+    rollback.push("tor", move || {
+        if let Some(Ok(mut tor)) = tor.as_ref().map(|tor| tor.lock()) {
+            tor.stop();
+        }
+        Ok(())
+    });
 
     Ok(())
 }
-
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
-use crate::errors::Errcode;
+use orjail::Errcode;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "orjail", about = "Container runtime that strictly forces traffic through TOR.")]
@@ -10,31 +10,49 @@ pub struct Args {
     debug: bool,
 
     /// Command to execute inside the container
-    #[structopt(short, long)]
+    #[structopt(default_value = "", short, long)]
     pub command: String,
 
     /// User ID to create inside the container, default to 0
     #[structopt(default_value = "0", short = "u", long = "uid")]
     pub uid: u32,
 
-    /// User ID to map inside the container, default to $UID
-    #[structopt(skip)]
-    pub real_uid: u32,
+    /// Bind a host path in the container as src:dst[:options], options are
+    /// a comma separated list of ro, rw, noexec, nosuid and nodev
+    #[structopt(short = "a", long = "add")]
+    pub addpaths: Vec<String>,
 
-    /// Group ID to map inside the container, default to $GID
-    #[structopt(skip)]
-    pub real_gid: u32,
+    /// Mount a tmpfs in the container as dst[:size]
+    #[structopt(long)]
+    pub tmpfs: Vec<String>,
 
-    #[structopt(parse(from_os_str), short = "a", long = "add")]
-    pub addpaths: Vec<PathBuf>,
+    /// Mount a host directory in the container as lower:dst, writes are
+    /// discarded on exit
+    #[structopt(long)]
+    pub overlay: Vec<String>,
 
     /// Directory to mount as root of the container
     #[structopt(default_value = "", short = "m", long = "mount")]
     pub mount_dir: String,
 
-    /// Name of the newtork namespace to create
-    #[structopt(default_value = "test", short, long)]
-    pub namespace: String,
+    /// Mount an overlay on the home of the user, writes are discarded on exit
+    #[structopt(long)]
+    pub ephemeral_home: bool,
+
+    /// Overlay on the home that starts empty and keeps the writes in
+    /// ~/.local/share/orjail/homes/<name>
+    #[structopt(long)]
+    pub persist_home: Option<String>,
+
+    /// Build a minimal root from the system directories of the host, with
+    /// fresh /tmp, /run, /dev and /proc
+    #[structopt(long, conflicts_with = "mount-dir")]
+    pub minimal_root: bool,
+
+    /// Name of the newtork namespace to create, defaults to the hostname of
+    /// the jail
+    #[structopt(short, long)]
+    pub namespace: Option<String>,
 
     /// Set custom TOR binary
     #[structopt(default_value = "", short, long)]
@@ -44,13 +62,101 @@ pub struct Args {
     #[structopt(default_value = "", short, long)]
     pub slirp4netns: String,
 
+    /// Security profile to apply, either a built-in name or a JSON file
+    #[structopt(default_value = "default", short, long)]
+    pub profile: String,
+
+    /// Seccomp profile to load, either a built-in name or an OCI seccomp JSON
+    /// file, overrides the one of the security profile
+    #[structopt(default_value = "", long)]
+    pub seccomp_profile: String,
+
+    /// Let the syscalls refused by the seccomp profile run, log them and
+    /// suggest a profile that allows them at exit
+    #[structopt(long)]
+    pub seccomp_audit: bool,
+
+    /// File where the suggested seccomp profile is written, default to stderr
+    #[structopt(parse(from_os_str), long)]
+    pub seccomp_audit_output: Option<PathBuf>,
+
+    /// Keep a minimal init as PID 1 of the jail, which forwards signals to
+    /// the command and reaps orphaned processes
+    #[structopt(long)]
+    pub init: bool,
+
+    /// Run the jail in the background once it is set up, its output is
+    /// logged and its terminal can be attached
+    #[structopt(long)]
+    pub detach: bool,
+
+    /// Limit the memory of the jail, in bytes with an optional K, M, G or T
+    /// suffix. Resource limits need a cgroup subtree delegated by systemd
+    #[structopt(long)]
+    pub memory_max: Option<String>,
+
+    /// Limit the number of processes of the jail
+    #[structopt(long)]
+    pub pids_max: Option<u64>,
+
+    /// Limit the CPU time of the jail as quota[/period], in microseconds,
+    /// the period defaults to 100000
+    #[structopt(long)]
+    pub cpu_max: Option<String>,
+
+    /// Weight of the jail for block IO, from 1 to 10000
+    #[structopt(long)]
+    pub io_weight: Option<u16>,
+
     /// Disable syscall filtering
     #[structopt(long)]
     pub disable_syscall: bool,
 
     /// Disable capabilities drop
     #[structopt(long)]
-    pub disable_capabilities: bool
+    pub disable_capabilities: bool,
+
+    #[structopt(subcommand)]
+    pub subcommand: Option<Subcommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Subcommand {
+    /// List the jails with their PIDs and TOR bootstrap status
+    List,
+    /// Run a command in a running jail, with the same restrictions as its
+    /// command
+    Exec {
+        /// ID of the jail, or PID of its orjail process or of its command
+        jail: String,
+        /// Command to execute, with its arguments after --
+        #[structopt(required = true)]
+        command: Vec<String>,
+    },
+    /// Stop a running jail, killing it if it does not exit in time
+    Stop {
+        /// ID of the jail, or PID of its orjail process or of its command
+        jail: String,
+    },
+    /// Print the output of a detached jail
+    Logs {
+        /// ID of the jail, or PID of its orjail process or of its command
+        jail: String,
+        /// Keep printing the output until the jail exits
+        #[structopt(short, long)]
+        follow: bool,
+    },
+    /// Attach the terminal to a detached jail, detach with Ctrl-]
+    Attach {
+        /// ID of the jail, or PID of its orjail process or of its command
+        jail: String,
+    },
+    /// Remove what jails that did not exit cleanly left behind
+    Gc {
+        /// Only list what would be removed
+        #[structopt(long)]
+        dry_run: bool,
+    },
 }
 
 pub fn parse_args() -> Result<Args, Errcode> {
@@ -65,13 +171,12 @@ pub fn parse_args() -> Result<Args, Errcode> {
     }
 
     // Validate arguments
-    // TODO this will be performed after a recheck of set_container_mountpoint
-    // if !args.mount_dir.exists() || !args.mount_dir.is_dir(){
-    //     return Err(Errcode::ArgumentInvalid("mount"));
-    // }
+    if !args.mount_dir.is_empty() && !PathBuf::from(&args.mount_dir).is_dir() {
+        return Err(Errcode::ArgumentInvalid("mount".to_string()));
+    }
 
-    if args.command.is_empty() {
-        return Err(Errcode::ArgumentInvalid("command"));
+    if args.command.is_empty() && args.subcommand.is_none() {
+        return Err(Errcode::ArgumentInvalid("command".to_string()));
     }
 
     Ok(args)
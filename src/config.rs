@@ -1,10 +1,17 @@
 use crate::errors::Errcode;
 use crate::hostname::generate_hostname;
+use crate::mountpoint::generate_root_path;
+use crate::mountspec::MountSpec;
+use crate::profile::Profile;
+use crate::resources::ResourceLimits;
+use crate::seccomp::SeccompProfile;
 use crate::slirp::{SlirpProcess, SlirpWrapper};
 use crate::tor::{TorProcess, TorWrapper};
 
+use nix::sys::signal::SigSet;
 use nix::unistd::Pid;
 use std::ffi::CString;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -13,55 +20,96 @@ use std::sync::{Arc, Mutex};
 pub struct ContainerOpts{
     pub path:       CString,
     pub argv:       Vec<CString>,
+    // Environment of the command as KEY=value, empty unless set
+    pub env:        Vec<CString>,
 
     pub uid:        u32,
     pub real_uid:   u32,
     pub real_gid:   u32,
     pub mount_dir:  Option<PathBuf>,
+    // Root pivoted into when mount_dir or minimal_root is set, /tmp/orjail.<random>
+    pub root:       Option<PathBuf>,
+    pub minimal_root: bool,
     pub hostname: String,
     pub namespace: String,
-    pub addpaths: Vec<(PathBuf, PathBuf)>,
+    pub mounts: Vec<MountSpec>,
     pub slirp_path: PathBuf,
     pub slirp_process: Option<SlirpWrapper>,
     pub tor_path: PathBuf,
     pub tor_process: Option<TorWrapper>,
     pub disable_syscall: bool,
     pub disable_capabilities: bool,
+    pub profile: Profile,
+    pub seccomp_profile: SeccompProfile,
+    pub seccomp_audit: bool,
+    pub seccomp_audit_output: Option<PathBuf>,
+    // Child end of the socketpair shared with the parent
+    pub fd: RawFd,
+    // Slave of the pty relayed by the parent, when run from a terminal
+    pub tty: Option<RawFd>,
+    // Write end of the ready pipe of a detached jail, closed by the child
+    pub ready_fd: Option<RawFd>,
+    // Signal mask of orjail before it blocked the termination signals,
+    // restored by the child
+    pub sigmask: Option<SigSet>,
+    pub init: bool,
+    pub limits: ResourceLimits,
 }
 
 impl ContainerOpts{
-    pub fn new(command: String, uid: u32, real_uid: u32, real_gid: u32, mount_dir: Option<PathBuf>, namespace: String, addpaths: Vec<(PathBuf, PathBuf)>, tor_path: PathBuf, slirp_path: PathBuf, disable_syscall: bool, disable_capabilities: bool) -> Result<ContainerOpts, Errcode> {
-        let argv: Vec<CString> = command.split_ascii_whitespace()
-            .map(|s| CString::new(s).expect("Cannot read arg")).collect();
-        let path = argv[0].clone();
+    pub fn new(command: Vec<String>, uid: u32, real_uid: u32, real_gid: u32, mount_dir: Option<PathBuf>, namespace: Option<String>, mounts: Vec<MountSpec>, tor_path: PathBuf, slirp_path: PathBuf, disable_syscall: bool, disable_capabilities: bool, profile: Profile, seccomp_profile: SeccompProfile, fd: RawFd) -> Result<ContainerOpts, Errcode> {
+        let argv: Vec<CString> = match command.into_iter().map(CString::new).collect() {
+            Ok(argv) => argv,
+            Err(_) => return Err(Errcode::ArgumentInvalid("command".to_string())),
+        };
+        let path = match argv.first() {
+            Some(path) => path.clone(),
+            None => return Err(Errcode::ArgumentInvalid("command".to_string())),
+        };
 
+        let hostname = generate_hostname()?;
         Ok( ContainerOpts {
                     path,
                     argv,
+                    env: vec![],
                     uid,
                     real_uid,
                     real_gid,
+                    root: mount_dir.as_ref().map(|_| generate_root_path()),
+                    minimal_root: false,
                     mount_dir,
-                    namespace,
-                    hostname: generate_hostname()?,
-                    addpaths,
+                    namespace: namespace.unwrap_or_else(|| hostname.clone()),
+                    hostname,
+                    mounts,
                     slirp_path,
                     slirp_process: None,
                     tor_path,
                     tor_process: None,
                     disable_syscall,
                     disable_capabilities,
+                    profile,
+                    seccomp_profile,
+                    seccomp_audit: false,
+                    seccomp_audit_output: None,
+                    fd,
+                    tty: None,
+                    ready_fd: None,
+                    sigmask: None,
+                    init: false,
+                    limits: ResourceLimits::default(),
         })
     }
 
-    pub fn spawn_slirp(&mut self, pid: Pid) {
-        self.slirp_process = Some(Arc::new(Mutex::new(SlirpProcess::new(pid, &self.slirp_path).unwrap())));
+    pub fn spawn_slirp(&mut self, pid: Pid) -> Result<(), Errcode> {
+        self.slirp_process = Some(Arc::new(Mutex::new(SlirpProcess::new(pid, &self.slirp_path)?)));
+        Ok(())
     }
 
-    pub fn spawn_tor(&mut self) {
+    pub fn spawn_tor(&mut self) -> Result<(), Errcode> {
         // TODO it should be in the configuration
         let tor_path = Path::new("/tmp/tor");
-        self.tor_process = Some(Arc::new(Mutex::new(TorProcess::new(tor_path, &self.tor_path).unwrap())));
+        self.tor_process = Some(Arc::new(Mutex::new(TorProcess::new(tor_path, &self.tor_path)?)));
+        Ok(())
     }
 
 }
@@ -1,17 +1,28 @@
-use crate::cli::Args;
-use crate::errors::Errcode;
+use crate::errors::{Errcode, SetupStage, in_stage};
 use crate::config::ContainerOpts;
 use crate::child::generate_child_process;
-use crate::mountpoint::clean_mounts;
-// use crate::resources::{clean_cgroups, restrict_resources};
+use crate::events::{EventSender, JailEvent};
+use crate::ipc::{generate_socketpair, recv_message, send_message, SetupMessage};
+use crate::jail::JailBuilder;
+use crate::mountpoint::{clean_mounts, generate_root_path};
+use crate::mountspec::MountSpec;
+use crate::namespaces::userns;
+use crate::profile::Profile;
+use crate::rollback::Rollback;
+use crate::pty::open_pty;
+use crate::resources::Cgroup;
+use crate::seccomp::SeccompProfile;
 
 use scan_fmt::scan_fmt;
 use nix::sys::stat::stat;
 use nix::sys::utsname::uname;
+use nix::sys::signal::{kill, Signal};
 use nix::sys::wait::waitpid;
-use nix::unistd::{getuid, getgid, Pid};
+use nix::unistd::{getuid, getgid, Pid, Uid, User};
+use std::ffi::CString;
 use std::fs::File;
 use std::io::Read;
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::path::PathBuf;
 use which::which;
 
@@ -20,125 +31,205 @@ const PROCFS_UNPRIVILEGED_NS: &str = "/proc/sys/kernel/unprivileged_userns_clone
 pub struct Container{
     pub config: ContainerOpts,
     pub child: Option<Pid>,
+    // Parent end of the socketpair, the child end is closed in the parent
+    // once the child is created
+    pub socket: OwnedFd,
+    pub child_socket: Option<OwnedFd>,
+    // Same for the pty, the slave becomes the terminal of the child
+    pub pty_master: Option<OwnedFd>,
+    pub pty_slave: Option<OwnedFd>,
+    // Lifecycle events, for the jails spawned with spawn_async
+    pub events: Option<EventSender>,
+    // Set when the jail has resource limits
+    pub cgroup: Option<Cgroup>,
 }
 
 impl Container {
-    pub fn new(args: Args) -> Result<Container, Errcode> {
-        let mut addpaths = vec![];
-        for ap_pair in args.addpaths.iter(){
-            let mut pair = ap_pair.to_str().unwrap().split(":");
-            let frompath = PathBuf::from(pair.next().unwrap())
-                .canonicalize().expect("Cannot canonicalize path")
-                .to_path_buf();
-            let mntpath = PathBuf::from(pair.next().unwrap())
-                .strip_prefix("/").expect("Cannot strip prefix from path")
-                .to_path_buf();
-            addpaths.push((frompath, mntpath));
+    pub fn new(jail: JailBuilder) -> Result<Container, Errcode> {
+        let mut mounts = jail.mounts;
+        for spec in mounts.iter() {
+            spec.validate()?;
+        }
+        if !mounts.is_empty() && jail.mount_dir.is_none() && !jail.minimal_root {
+            log::warn!("Additional mounts are only applied with --mount or --minimal-root");
         }
 
-        // match default value for uid/gid
-        let real_uid = match args.real_uid {
-            0 => getuid().as_raw(),
-            _        => args.real_uid,
-        };
+        let real_uid = getuid().as_raw();
+        let real_gid = getgid().as_raw();
+
+        if let Some(persist) = jail.home.as_ref() {
+            let home = match User::from_uid(Uid::from_raw(real_uid)) {
+                Ok(Some(user)) => user.dir,
+                _ => {
+                    log::error!("Can not find the home of user {}", real_uid);
+                    return Err(Errcode::ArgumentInvalid("persist-home".to_string()));
+                }
+            };
+            mounts.push(MountSpec::home(home, persist.as_deref())?);
+        }
+
+        let tor_path = check_binary(jail.tor.as_ref(), "tor")?;
+        let slirp_path = check_binary(jail.slirp4netns.as_ref(), "slirp4netns")?;
 
-        let real_gid = match args.real_gid {
-            0 => getgid().as_raw(),
-            _        => args.real_uid,
+        let profile = match jail.profile {
+            Some(profile) => profile,
+            None => Profile::load("default")?,
+        };
+        let seccomp_profile = match jail.seccomp_profile {
+            Some(seccomp_profile) => seccomp_profile,
+            None => SeccompProfile::load(&profile.seccomp.profile)?,
         };
 
-        let tor_path = check_binary(&args.tor, "tor")?;
-        let slirp_path = check_binary(&args.slirp4netns, "slirp4netns")?;
+        // pivot_root needs an absolute path
+        let mount_dir = match jail.mount_dir {
+            None => None,
+            Some(mount_dir) => match mount_dir.canonicalize() {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    log::error!("Can not resolve mount directory {}: {}", mount_dir.display(), e);
+                    return Err(Errcode::ArgumentInvalid("mount".to_string()));
+                }
+            }
+        };
 
-        let mount_dir;
-        if args.mount_dir.is_empty() {
-            mount_dir = None;
-        } else {
-            mount_dir = Some(PathBuf::from(args.mount_dir));
-        }
+        let (socket, child_socket) = generate_socketpair()?;
+        let (pty_master, pty_slave) = match open_pty(jail.detach)? {
+            Some(pty) => (Some(pty.master), Some(pty.slave)),
+            None => (None, None),
+        };
 
-        let config = ContainerOpts::new(
-            args.command,
-            args.uid,
+        let mut config = ContainerOpts::new(
+            jail.command,
+            jail.uid,
             real_uid,
             real_gid,
             mount_dir,
-            args.namespace,
-            addpaths,
+            jail.namespace,
+            mounts,
             tor_path,
             slirp_path,
-            args.disable_syscall,
-            args.disable_capabilities)?;
+            jail.disable_syscall,
+            jail.disable_capabilities,
+            profile,
+            seccomp_profile,
+            child_socket.as_raw_fd())?;
+        config.env = match jail.env.iter().map(|(key, value)| CString::new(format!("{key}={value}"))).collect() {
+            Ok(env) => env,
+            Err(_) => return Err(Errcode::ArgumentInvalid("env".to_string())),
+        };
+        config.seccomp_audit = jail.seccomp_audit;
+        config.seccomp_audit_output = jail.seccomp_audit_output;
+        config.init = jail.init;
+        config.limits = jail.limits;
+        config.tty = pty_slave.as_ref().map(|fd| fd.as_raw_fd());
+        if jail.minimal_root {
+            config.minimal_root = true;
+            config.root = Some(generate_root_path());
+        }
         Ok(Container {
             config,
             child: None,
+            socket,
+            child_socket: Some(child_socket),
+            pty_master,
+            pty_slave,
+            events: jail.events,
+            cgroup: None,
             })
         }
 
     pub fn create(&mut self) -> Result<(), Errcode> {
+        let mut rollback = Rollback::new();
         let pid = generate_child_process(&mut self.config)?;
-        // TODO investigate why cgroup constraints result in a deadlock
-        // restrict_resources(&self.config.hostname, pid)?;
+        // The child undoes its own steps before reporting an error, killing
+        // it takes down the namespaces and what still runs in them
+        rollback.push("child process", move || {
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+            Ok(())
+        });
+        self.child_socket = None;
+        self.pty_slave = None;
+        self.setup(pid, &mut rollback)?;
+        rollback.commit();
         self.child = Some(pid);
 
-        log::debug!("Creation finished, PID: {:?} ", self.child.unwrap());
+        log::debug!("Creation finished, PID: {:?} ", pid);
         Ok(())
     }
 
-    pub fn clean_exit(&mut self) -> Result<(), Errcode>{
-        log::debug!("Cleaning container");
-
-        // Here we can not catch errors as its not returned
-
-        clean_mounts(&self.config.mount_dir, &self.config.hostname)?;
+    // Parent side of the setup protocol, see SetupMessage. The errors of the
+    // child come tagged with their stage, a child that exits without a word
+    // closes its end of the socketpair.
+    fn setup(&mut self, pid: Pid, rollback: &mut Rollback) -> Result<(), Errcode> {
+        let socket = self.socket.as_raw_fd();
+        match recv_message(socket) {
+            Ok(SetupMessage::NamespacesCreated) => {},
+            Ok(message) => return Err(Errcode::SocketError(format!("Unexpected message from the child: {:?}", message))),
+            Err(e) => return Err(Errcode::ChildProcessError(format!("The child exited before creating its namespaces: {e}"))),
+        }
+        userns(pid, self.config.real_uid, self.config.real_gid, self.config.uid).map_err(in_stage(SetupStage::Userns))?;
+        self.send_event(JailEvent::NamespacesReady);
+        // The child waits for NetworkReady before anything else, it only
+        // unshares its cgroup namespace once in the cgroup of the jail
+        self.cgroup = Cgroup::create(&self.config.hostname, &self.config.limits).map_err(in_stage(SetupStage::Resources))?;
+        if let Some(cgroup) = self.cgroup.as_ref() {
+            cgroup.add(pid).map_err(in_stage(SetupStage::Resources))?;
+        }
+        self.config.spawn_slirp(pid).map_err(in_stage(SetupStage::Network))?;
+        let slirp = self.config.slirp_process.clone();
+        rollback.push("slirp4netns", move || {
+            if let Some(Ok(mut slirp)) = slirp.as_ref().map(|slirp| slirp.lock()) {
+                let _ = slirp.process.kill();
+                let _ = slirp.process.wait();
+            }
+            Ok(())
+        });
+        send_message(socket, &SetupMessage::NetworkReady)?;
+        self.send_event(JailEvent::NetworkReady);
 
-        // if let Err(e) = clean_cgroups(&self.config.hostname) {
-        //     log::error!("Cgroups cleaning failed: {}", e);
-        //     return Err(e);
-        // }
-        Ok(())
+        match recv_message(socket) {
+            Ok(SetupMessage::SetupDone) => {
+                self.send_event(JailEvent::Exec);
+                Ok(())
+            }
+            Ok(SetupMessage::Failed(e)) => Err(e),
+            Ok(message) => Err(Errcode::SocketError(format!("Unexpected message from the child: {:?}", message))),
+            Err(e) => Err(Errcode::ChildProcessError(format!("The child exited without reporting its setup: {e}"))),
+        }
     }
 
-}
-
-pub fn start(args: Args) -> Result<(), Errcode> {
-    check_compatibility()?;
-
-    let mut container = Container::new(args)?;
-    if let Err(e) = container.create(){
-        container.clean_exit()?;
-        log::error!("Error while creating container: {:?}", e);
-        return Err(e);
+    fn send_event(&self, event: JailEvent) {
+        if let Some(events) = self.events.as_ref() {
+            events(event);
+        }
     }
-    // Set container cgroup constraints
 
-    log::debug!("Container child PID: {:?}", container.child.unwrap());
-    container.config.spawn_slirp(container.child.unwrap());
+    pub fn clean_exit(&mut self) -> Result<(), Errcode>{
+        log::debug!("Cleaning container");
 
-    wait_child(container.child)?;
-    log::debug!("Finished, cleaning & exit");
+        // Here we can not catch errors as its not returned
 
-    container.clean_exit()
-}
+        clean_mounts(&self.config.root, &self.config.hostname)?;
 
-pub fn wait_child(pid: Option<Pid>) -> Result<(), Errcode> {
-    if let Some(child_pid) = pid {
-        log::debug!("Waiting for child (pid {}) to finish", child_pid);
-        if let Err(e) = waitpid(child_pid, None) {
-            log::error!("Error while waiting for child to finish: {:?}", e);
-            return Err(Errcode::ContainerError(format!("Error while waiting for child to finish: {:?}", e)));
-        }
+        // Removed once dropped, the processes of the jail are gone by now
+        self.cgroup = None;
+        Ok(())
     }
-    Ok(())
+
 }
 
 pub const MINIMAL_KERNEL_VERSION: f32 = 4.8;
 
 pub fn check_compatibility() -> Result<(), Errcode> {
-    let host = uname();
-    log::debug!("Linux release: {}", host.unwrap().release().to_str().unwrap());
+    let host = match uname() {
+        Ok(host) => host,
+        Err(e) => return Err(Errcode::SysError("Can not get the kernel release".to_string(), e)),
+    };
+    let release = host.release().to_string_lossy();
+    log::debug!("Linux release: {}", release);
 
-    if let Ok(version) = scan_fmt!(host.unwrap().release().to_str().unwrap(), "{f}.{}", f32) {
+    if let Ok(version) = scan_fmt!(&release, "{f}.{}", f32) {
         if version < MINIMAL_KERNEL_VERSION {
             return Err(Errcode::NotSupported(format!("Kernel Version {version} not supported, minimum {MINIMAL_KERNEL_VERSION}")));
         }
@@ -146,8 +237,8 @@ pub fn check_compatibility() -> Result<(), Errcode> {
         return Err(Errcode::ContainerError("Can not parse kernel release version".to_string()));
     }
 
-    if host.unwrap().machine() != "x86_64" {
-        return Err(Errcode::NotSupported(format!("Machine architecture {:?} not supported", host.unwrap().machine())));
+    if host.machine() != "x86_64" {
+        return Err(Errcode::NotSupported(format!("Machine architecture {:?} not supported", host.machine())));
     }
 
     // Check that unprivileged namespaces are enabled
@@ -172,22 +263,22 @@ pub fn check_compatibility() -> Result<(), Errcode> {
 
 }
 
-fn check_binary(arg: &String, name: &str) -> Result<PathBuf, Errcode> {
-    if arg.is_empty() {
-        match which(name) {
-            Ok(path) => return Ok(path),
+fn check_binary(path: Option<&PathBuf>, name: &str) -> Result<PathBuf, Errcode> {
+    match path {
+        None => match which(name) {
+            Ok(path) => Ok(path),
             Err(e) => {
                 log::error!("Can not find {} in PATH, please be sure that is available or install it", name);
-                return Err(Errcode::ContainerError(format!("Can not find {} in PATH: {}", name, e)));
+                Err(Errcode::ContainerError(format!("Can not find {} in PATH: {}", name, e)))
+            }
+        },
+        Some(path) => {
+            if let Err(e) = stat(path) {
+                log::error!("Can not stat {} at {}: {}", name, path.display(), e);
+                Err(Errcode::SysError(format!("Can not find {} at path {}", name, path.display()), e))
+            } else {
+                Ok(path.clone())
             }
-        };
-    } else {
-        let path = PathBuf::from(arg);
-        if let Err(e) = stat(&path) {
-            log::error!("Can not stat {} at {}: {}", name, arg, e);
-            return Err(Errcode::ContainerError(format!("Can not find {} at path {}: {}", name, arg, e)));
-        } else {
-            return Ok(path);
         }
     }
 }
@@ -0,0 +1,114 @@
+// Detached jails: orjail goes to the background once the jail is set up, the
+// output of the jail is logged and its pty can be attached later
+use crate::errors::Errcode;
+use crate::ipc::recv_fd;
+use crate::pty::start_relay;
+use crate::state::{find_jail, state_root, ATTACH_SOCKET};
+
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{dup2, fork, pipe2, setsid, ForkResult};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::exit;
+
+// Held by the background orjail until the jail is ready
+pub struct Detached {
+    ready: File,
+}
+
+// The foreground process waits for the background one to report the jail as
+// ready, prints its ID and exits. Setup errors are still printed on its
+// terminal, the background orjail only leaves it once the jail runs.
+pub fn daemonize() -> Result<Detached, Errcode> {
+    // Not inherited by the jail, the foreground process reads until every
+    // copy of the write end is closed
+    let (read_end, write_end) = match pipe2(OFlag::O_CLOEXEC) {
+        Ok((read_end, write_end)) => unsafe { (File::from_raw_fd(read_end), File::from_raw_fd(write_end)) },
+        Err(e) => return Err(Errcode::SysError("Can not create pipe".to_string(), e)),
+    };
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { .. }) => {
+            drop(write_end);
+            let mut id = String::new();
+            let mut read_end = read_end;
+            match read_end.read_to_string(&mut id) {
+                Ok(_) if !id.is_empty() => {
+                    println!("{}", id);
+                    exit(0);
+                }
+                _ => {
+                    log::error!("The detached jail could not be started");
+                    exit(1);
+                }
+            }
+        }
+        Ok(ForkResult::Child) => {
+            drop(read_end);
+            // No longer part of the session of the terminal, its hangup does
+            // not reach the jail
+            if let Err(e) = setsid() {
+                return Err(Errcode::SysError("Can not create a new session".to_string(), e));
+            }
+            Ok(Detached { ready: write_end })
+        }
+        Err(e) => Err(Errcode::SysError("Can not fork in the background".to_string(), e)),
+    }
+}
+
+impl Detached {
+    pub fn fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+
+    // From now on the messages of orjail go to orjail.log in the state
+    // directory of the jail
+    pub fn ready(mut self, id: &str, state_dir: &Path) -> Result<(), Errcode> {
+        let null = match open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+            Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+            Err(e) => return Err(Errcode::SysError("Can not open /dev/null".to_string(), e)),
+        };
+        let log_path = state_dir.join("orjail.log");
+        let log = match File::create(&log_path) {
+            Ok(file) => file,
+            Err(e) => return Err(Errcode::IoError(format!("Can not create {}", log_path.display()), e)),
+        };
+        for (fd, target) in [(null.as_raw_fd(), 0), (null.as_raw_fd(), 1), (log.as_raw_fd(), 2)] {
+            if let Err(e) = dup2(fd, target) {
+                return Err(Errcode::SysError(format!("Can not redirect {}", target), e));
+            }
+        }
+
+        if let Err(e) = self.ready.write_all(id.as_bytes()) {
+            return Err(Errcode::IoError("Can not report the jail as ready".to_string(), e));
+        }
+        Ok(())
+    }
+}
+
+// Take over the pty of a detached jail until DETACH_KEY is typed or the jail
+// exits
+pub fn attach(jail: &str) -> Result<(), Errcode> {
+    let state = find_jail(jail)?;
+    let path = state_root().join(&state.id).join(ATTACH_SOCKET);
+    let stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(e) => return Err(Errcode::IoError(format!("Jail {} is not detached", state.id), e)),
+    };
+    let master = match recv_fd(stream.as_raw_fd()) {
+        Ok(master) => master,
+        Err(_) => return Err(Errcode::StateError(format!("Jail {} is already attached", state.id))),
+    };
+
+    eprintln!("Attached to jail {}, detach with Ctrl-]\r", state.id);
+    let (_terminal, relay) = start_relay(master, true)?;
+    if relay.join().is_err() {
+        log::error!("Terminal relay thread panicked");
+    }
+    drop(stream);
+    Ok(())
+}
@@ -1,11 +1,16 @@
+use nix::errno::Errno;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
 use std::process::exit;
 use thiserror::Error;
 
 // Allows to display a variant with the format {:?}
-#[derive(Debug, Error)]
+// Serialized for the child to report its setup errors to the parent
+#[derive(Debug, Error, Serialize, Deserialize)]
 pub enum Errcode{
     #[error("argument {0} is not valid")]
-    ArgumentInvalid(&'static str),
+    ArgumentInvalid(String),
     #[error("Error in setting capabilities {0}")]
     Capabilities(String),
     #[error("Error in container creation {0}")]
@@ -14,6 +19,10 @@ pub enum Errcode{
     ChildProcessError(String),
     #[error("Error while setting hostname {0}")]
     HostnameError(String),
+    #[error("Error in Landlock filesystem sandbox {0}")]
+    LandlockError(String),
+    #[error("Error while setting up the terminal {0}")]
+    TerminalError(String),
     #[error("Error while mounting container fs {0}")]
     MountsError(String),
     #[error("Error in namespace creation {0}")]
@@ -22,12 +31,22 @@ pub enum Errcode{
     NetworkError(String),
     #[error("Functionality not supported")]
     NotSupported(String),
+    #[error("Invalid profile: {0}")]
+    ProfileError(String),
     #[error("Not possible to define cgroups: {0}")]
     ResourcesError(String),
     #[error("Error in Slirp Process creation: {0}")]
     SlirpError(String),
+    #[error("{0}: {1}")]
+    IoError(String, #[source] #[serde(with = "io_error")] io::Error),
+    #[error("{0}: {1}")]
+    SysError(String, #[source] #[serde(with = "errno")] Errno),
     #[error("Error in IPC socket communication: {0}")]
     SocketError(String),
+    #[error("Error in jail state: {0}")]
+    StateError(String),
+    #[error("Jail setup failed at stage {0}: {1}")]
+    SetupError(SetupStage, #[source] Box<Errcode>),
     #[error("Unable to define container syscalls: {0}")]
     SyscallsError(String),
     #[error("Error with tor instance {0}")]
@@ -37,19 +56,90 @@ pub enum Errcode{
 impl Errcode{
     // Translate an Errcode::X into a number to return (the Unix way)
     pub fn get_retcode(&self) -> i32 {
-        1 // Everything != 0 will be treated as an error
+        match self {
+            Errcode::SetupError(stage, _) => stage.retcode(),
+            _ => 1, // Everything != 0 will be treated as an error
+        }
+    }
+}
+
+// Steps of the setup of a jail, each one exits with its own code
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SetupStage {
+    Hostname,
+    Userns,
+    Mounts,
+    Network,
+    Nftables,
+    Tor,
+    Terminal,
+    Capabilities,
+    Landlock,
+    Init,
+    Seccomp,
+    Resources,
+}
+
+impl SetupStage {
+    pub fn retcode(&self) -> i32 {
+        match self {
+            SetupStage::Hostname => 10,
+            SetupStage::Userns => 11,
+            SetupStage::Mounts => 12,
+            SetupStage::Network => 13,
+            SetupStage::Nftables => 14,
+            SetupStage::Tor => 15,
+            SetupStage::Terminal => 16,
+            SetupStage::Capabilities => 17,
+            SetupStage::Landlock => 18,
+            SetupStage::Init => 19,
+            SetupStage::Seccomp => 20,
+            SetupStage::Resources => 21,
+        }
     }
 }
 
+impl fmt::Display for SetupStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SetupStage::Hostname => "hostname",
+            SetupStage::Userns => "userns",
+            SetupStage::Mounts => "mounts",
+            SetupStage::Network => "network",
+            SetupStage::Nftables => "nftables",
+            SetupStage::Tor => "tor",
+            SetupStage::Terminal => "terminal",
+            SetupStage::Capabilities => "capabilities",
+            SetupStage::Landlock => "landlock",
+            SetupStage::Init => "init",
+            SetupStage::Seccomp => "seccomp",
+            SetupStage::Resources => "resources",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Tags the error of a setup step with its stage:
+// step().map_err(in_stage(SetupStage::Mounts))?
+pub fn in_stage(stage: SetupStage) -> impl FnOnce(Errcode) -> Errcode {
+    move |e| Errcode::SetupError(stage, Box::new(e))
+}
+
 impl From<rtnetlink::Error> for Errcode {
     fn from(err: rtnetlink::Error) -> Self {
         Errcode::NetworkError(err.to_string())
     }
 }
 
-impl From<std::io::Error> for Errcode {
-    fn from(err: std::io::Error) -> Self {
-        Errcode::NetworkError(err.to_string())
+impl From<io::Error> for Errcode {
+    fn from(err: io::Error) -> Self {
+        Errcode::IoError("I/O error".to_string(), err)
+    }
+}
+
+impl From<Errno> for Errcode {
+    fn from(err: Errno) -> Self {
+        Errcode::SysError("System call failed".to_string(), err)
     }
 }
 
@@ -59,18 +149,32 @@ impl From<std::net::AddrParseError> for Errcode {
     }
 }
 
-pub fn exit_with_retcode(res: Result<(), Errcode>) {
-    match res {
-        Ok(_) => {
-            log::debug!("Exit without any error, returning 0");
-            exit(0);
-        },
-
-        Err(e) => {
-            let retcode = e.get_retcode();
-            log::error!("Error on exit:\n\t{}\n\tReturning {}", e, retcode);
-            exit(retcode);
-        }
+// The errors of the child and of the helper of spawn_async cross a socket,
+// system errors keep their errno
+mod io_error {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(err: &io::Error, serializer: S) -> Result<S::Ok, S::Error> {
+        (err.raw_os_error(), err.to_string()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<io::Error, D::Error> {
+        Ok(match <(Option<i32>, String)>::deserialize(deserializer)? {
+            (Some(errno), _) => io::Error::from_raw_os_error(errno),
+            (None, message) => io::Error::other(message),
+        })
+    }
+}
+
+mod errno {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(err: &Errno, serializer: S) -> Result<S::Ok, S::Error> {
+        (*err as i32).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Errno, D::Error> {
+        Ok(Errno::from_i32(i32::deserialize(deserializer)?))
     }
 }
 
@@ -0,0 +1,72 @@
+// Lifecycle events of a jail spawned with JailBuilder::spawn_async, in the
+// order they happen
+use crate::state::JailState;
+
+use futures::Stream;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+const TOR_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JailEvent {
+    // The child runs in its namespaces, its uid and gid maps are written
+    NamespacesReady,
+    // slirp4netns connected the network namespace of the jail
+    NetworkReady,
+    // The command is about to be executed, the setup of the jail is done
+    Exec,
+    // Bootstrap progress of the TOR of the jail, in percent
+    TorBootstrap(u8),
+    // The command exited, the jail is cleaned up
+    Exited(ExitStatus),
+}
+
+// Called by the setup of the jail, in the helper process of spawn_async
+pub(crate) type EventSender = Box<dyn Fn(JailEvent) + Send>;
+
+// Ends once the jail exited
+pub struct JailEvents {
+    receiver: UnboundedReceiver<JailEvent>,
+}
+
+impl JailEvents {
+    pub(crate) fn new(receiver: UnboundedReceiver<JailEvent>) -> JailEvents {
+        JailEvents { receiver }
+    }
+}
+
+impl Stream for JailEvents {
+    type Item = JailEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<JailEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+// Follows the TOR log of the jail until it is bootstrapped, or until nobody
+// listens to the events anymore
+pub(crate) async fn watch_tor_bootstrap(state: JailState, events: UnboundedSender<JailEvent>) {
+    let mut interval = tokio::time::interval(TOR_POLL_INTERVAL);
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        if events.is_closed() {
+            return;
+        }
+        let progress = match state.tor_bootstrap().and_then(|progress| progress.trim_end_matches('%').parse::<u8>().ok()) {
+            Some(progress) => progress,
+            None => continue,
+        };
+        if last != Some(progress) {
+            last = Some(progress);
+            let _ = events.send(JailEvent::TorBootstrap(progress));
+        }
+        if progress >= 100 {
+            return;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// orjailrs exec: run another command in a running jail, in the namespaces of
+// its command and under the same restrictions
+use crate::capabilities::setcapabilities;
+use crate::errors::{Errcode, exit_with_errcode};
+use crate::init::start_init;
+use crate::ipc::{generate_socketpair, send_fd};
+use crate::landlock::setlandlock;
+use crate::pty::{open_pty, set_controlling_terminal, start_relay};
+use crate::resources::join_cgroup;
+use crate::signals::ParentSignals;
+use crate::state::{find_jail, JailState};
+use crate::supervisor::start_supervisor;
+use crate::syscalls::setsyscalls;
+
+use nix::fcntl::{open, OFlag};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{chdir, execve, fork, getpid, getuid, ForkResult};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::exit;
+
+// The user namespace comes first, it gives the capabilities needed to join
+// the other ones
+const NAMESPACES: [(&str, CloneFlags); 7] = [
+    ("user", CloneFlags::CLONE_NEWUSER),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+];
+
+// Returns the exit code of the command, or 128 + the signal that killed it
+pub fn exec(jail: &str, command: &[String]) -> Result<i32, Errcode> {
+    let state = find_jail(jail)?;
+    let argv: Vec<CString> = match command.iter().map(|arg| CString::new(arg.as_str())).collect() {
+        Ok(argv) => argv,
+        Err(_) => return Err(Errcode::ArgumentInvalid("exec".to_string())),
+    };
+    if argv.is_empty() {
+        return Err(Errcode::ArgumentInvalid("exec".to_string()));
+    }
+    log::info!("Running {:?} in jail {}", command, state.id);
+
+    // Opened from the host, before /proc is the one of the jail
+    let mut namespaces = vec![];
+    for (name, flag) in NAMESPACES.iter() {
+        let path = format!("/proc/{}/ns/{}", state.child_pid, name);
+        match open(path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(fd) => namespaces.push((unsafe { File::from_raw_fd(fd) }, *flag)),
+            Err(e) => return Err(Errcode::SysError(format!("Can not open {}", path), e)),
+        }
+    }
+
+    let pty = open_pty(false)?;
+    let (socket, child_socket) = generate_socketpair()?;
+    let uid = getuid().as_raw();
+
+    // setns needs a single threaded process, so it is done in a child that
+    // then runs the command as the init of the jail would
+    let child = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let tty = pty.as_ref().map(|pty| pty.slave.as_raw_fd());
+            if let Err(e) = enter_jail(&state, namespaces, tty, uid, child_socket.as_raw_fd()) {
+                exit_with_errcode(e);
+            }
+            let Err(e) = execve::<CString, CString>(&argv[0], &argv, &[]);
+            log::error!("Error while trying to perform execve {:?}", e);
+            exit(1)
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => return Err(Errcode::SysError("Can not fork to enter the jail".to_string(), e)),
+    };
+    drop(child_socket);
+
+    let mut signals = ParentSignals::block()?;
+    let (_terminal, relay) = match pty {
+        Some(pty) => {
+            drop(pty.slave);
+            let (guard, handle) = start_relay(pty.master, false)?;
+            (Some(guard), Some(handle))
+        }
+        None => (None, None),
+    };
+    let supervisor = if !state.disable_syscall && state.profile.supervisor.enabled() {
+        Some(start_supervisor(socket.as_raw_fd(), state.profile.supervisor.clone(), false))
+    } else {
+        None
+    };
+
+    let status = signals.wait_child(child)?;
+    log::debug!("Command in jail {} finished with {:?}", state.id, status);
+    if let Some(handle) = relay {
+        if handle.join().is_err() {
+            log::error!("Terminal relay thread panicked");
+        }
+    }
+    if let Some(handle) = supervisor {
+        if handle.join().is_err() {
+            log::error!("Seccomp supervisor thread panicked");
+        }
+    }
+    Ok(match status {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 0,
+    })
+}
+
+fn enter_jail(state: &JailState, namespaces: Vec<(File, CloneFlags)>, tty: Option<RawFd>, uid: u32, socket: RawFd) -> Result<(), Errcode> {
+    // Under the limits of the jail, before its cgroup namespace is joined
+    if let Some(cgroup) = state.cgroup.as_ref() {
+        join_cgroup(cgroup, getpid())?;
+    }
+    for (fd, flag) in namespaces {
+        if let Err(e) = setns(fd, flag) {
+            return Err(Errcode::SysError(format!("Can not join namespace {:?} of jail {}", flag, state.id), e));
+        }
+    }
+    if let Err(e) = chdir("/") {
+        return Err(Errcode::SysError("Can not change directory to the root of the jail".to_string(), e));
+    }
+    if let Some(tty) = tty {
+        set_controlling_terminal(tty)?;
+    }
+
+    // Only the children join the PID namespace, the command is forked by an
+    // init that waits for it and forwards the signals
+    if let Some(retcode) = start_init(tty, socket)? {
+        exit(retcode as i32);
+    }
+    // Killed with its init, which is what orjail kills after the grace period
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } < 0 {
+        return Err(Errcode::ChildProcessError("Can not set parent death signal".to_string()));
+    }
+
+    if !state.disable_capabilities {
+        setcapabilities(&state.profile.capabilities)?;
+    }
+    if state.profile.landlock.enabled {
+        setlandlock(&state.profile.landlock, uid, &state.profile.devices.allowed)?;
+    }
+    if !state.disable_syscall {
+        if let Some(notify_fd) = setsyscalls(&state.profile.seccomp, &state.seccomp_profile, &state.profile.supervisor, false, socket)? {
+            send_fd(socket, notify_fd)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+// Async interface of a jail, for running many of them from one tokio
+// runtime: each jail is set up and waited for by a helper process, see
+// helper.rs, the JailHandle resolves to the exit status of the command once
+// the jail is cleaned up.
+use crate::errors::Errcode;
+use crate::events::{watch_tor_bootstrap, JailEvent, JailEvents};
+use crate::helper::{HelperMessage, HelperRequest, HELPER_ENV, HELPER_FD};
+use crate::jail::JailBuilder;
+use crate::state::JailState;
+use crate::tor::newnym;
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{dup2, Pid};
+use std::env;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+impl JailBuilder {
+    // Must be called from a tokio runtime, in a program that calls
+    // orjail::run_helper first in main. The jail can not be detached, gets
+    // no terminal, and the signals of the calling process are left alone.
+    pub async fn spawn_async(self) -> Result<JailHandle, Errcode> {
+        if self.detach {
+            return Err(Errcode::ArgumentInvalid("detach".to_string()));
+        }
+        if self.forward_signals {
+            return Err(Errcode::ArgumentInvalid("forward_signals".to_string()));
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        // Dropping this future closes the socket of the helper, which then
+        // stops the jail and cleans it up
+        let (mut helper, mut messages) = start_helper(HelperRequest::Spawn(Box::new(self))).await?;
+        let state = loop {
+            match next_message(&mut messages).await? {
+                HelperMessage::Started(state) => break *state,
+                HelperMessage::Failed(e) => {
+                    let _ = helper.wait().await;
+                    return Err(e);
+                }
+                message => forward_event(message, &sender),
+            }
+        };
+        let child = Pid::from_raw(state.child_pid);
+
+        let tor = tokio::spawn(watch_tor_bootstrap(state.clone(), sender.clone()));
+        let wait = tokio::spawn(async move {
+            // Without an exit status from the helper the jail is reported as
+            // killed
+            let status = loop {
+                match next_message(&mut messages).await {
+                    Ok(HelperMessage::Exited(status)) => break ExitStatus::from_raw(status),
+                    Ok(message) => forward_event(message, &sender),
+                    Err(e) => {
+                        log::error!("Error while waiting for the jail: {}", e);
+                        break ExitStatus::from_raw(Signal::SIGKILL as i32);
+                    }
+                }
+            };
+            let _ = helper.wait().await;
+            tor.abort();
+            let _ = sender.send(JailEvent::Exited(status));
+            status
+        });
+
+        Ok(JailHandle {
+            state,
+            child,
+            wait,
+            events: Some(JailEvents::new(receiver)),
+        })
+    }
+}
+
+// Awaiting the handle waits for the command of the jail to exit. Dropping it
+// kills the jail, which is then cleaned up in the background.
+pub struct JailHandle {
+    state: JailState,
+    child: Pid,
+    wait: JoinHandle<ExitStatus>,
+    events: Option<JailEvents>,
+}
+
+impl JailHandle {
+    pub fn id(&self) -> &str {
+        &self.state.id
+    }
+
+    pub fn state(&self) -> &JailState {
+        &self.state
+    }
+
+    // The events since the jail was spawned, only the first call gets them
+    pub fn events(&mut self) -> Option<JailEvents> {
+        self.events.take()
+    }
+
+    pub fn kill(&self, signal: Signal) -> Result<(), Errcode> {
+        // Once the task is done the PID of the child may be reused
+        if self.wait.is_finished() {
+            return Ok(());
+        }
+        match kill(self.child, signal) {
+            Ok(_) | Err(Errno::ESRCH) => Ok(()),
+            Err(e) => Err(Errcode::SysError(format!("Can not send {} to the jail", signal), e)),
+        }
+    }
+
+    // Runs command in the jail and waits for it, see orjailrs exec
+    pub async fn exec(&self, command: &[String]) -> Result<i32, Errcode> {
+        let request = HelperRequest::Exec { id: self.state.id.clone(), command: command.to_vec() };
+        let (mut helper, mut messages) = start_helper(request).await?;
+        let result = match next_message(&mut messages).await? {
+            HelperMessage::Exited(status) => Ok(ExitStatus::from_raw(status).code().unwrap_or(0)),
+            HelperMessage::Failed(e) => Err(e),
+            message => Err(Errcode::SocketError(format!("Unexpected message from the helper: {:?}", message))),
+        };
+        let _ = helper.wait().await;
+        result
+    }
+
+    pub async fn newnym(&self) -> Result<(), Errcode> {
+        let tor_data = self.state.tor_data.clone();
+        match spawn_blocking(move || newnym(&tor_data)).await {
+            Ok(result) => result,
+            Err(e) => Err(Errcode::TorError(format!("The request to TOR did not finish: {e}"))),
+        }
+    }
+}
+
+impl Future for JailHandle {
+    type Output = ExitStatus;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<ExitStatus> {
+        match Pin::new(&mut self.wait).poll(cx) {
+            Poll::Ready(Ok(status)) => Poll::Ready(status),
+            Poll::Ready(Err(e)) => {
+                log::error!("The wait of jail {} did not finish: {}", self.state.id, e);
+                Poll::Ready(ExitStatus::from_raw(Signal::SIGKILL as i32))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for JailHandle {
+    fn drop(&mut self) {
+        let _ = self.kill(Signal::SIGKILL);
+    }
+}
+
+type Messages = Lines<BufReader<UnixStream>>;
+
+// Executes the program again as a helper, in its own process group, and sends
+// it the request
+async fn start_helper(request: HelperRequest) -> Result<(Child, Messages), Errcode> {
+    // A program that does not call run_helper would run again as a helper
+    // and could start helpers of its own
+    if env::var_os(HELPER_ENV).is_some() {
+        log::error!("spawn_async needs orjail::run_helper to be called first in main");
+        return Err(Errcode::ContainerError("orjail helper not started".to_string()));
+    }
+
+    let (socket, helper_socket) = match std::os::unix::net::UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => return Err(Errcode::IoError("Can not create the helper socket".to_string(), e)),
+    };
+    let helper_fd = helper_socket.as_raw_fd();
+    let mut command = Command::new("/proc/self/exe");
+    command.env(HELPER_ENV, "1").stdin(Stdio::null()).process_group(0);
+    // Between fork and exec, only async-signal-safe calls
+    unsafe {
+        command.pre_exec(move || {
+            if helper_fd == HELPER_FD {
+                if libc::fcntl(HELPER_FD, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            }
+            dup2(helper_fd, HELPER_FD).map(|_| ()).map_err(io::Error::from)
+        });
+    }
+    let helper = match command.spawn() {
+        Ok(helper) => helper,
+        Err(e) => return Err(Errcode::IoError("Can not start the orjail helper".to_string(), e)),
+    };
+    drop(helper_socket);
+
+    let mut socket = match socket.set_nonblocking(true).and_then(|_| UnixStream::from_std(socket)) {
+        Ok(socket) => socket,
+        Err(e) => return Err(Errcode::IoError("Can not use the helper socket".to_string(), e)),
+    };
+    let mut line = match serde_json::to_string(&request) {
+        Ok(line) => line,
+        Err(e) => return Err(Errcode::SocketError(format!("Can not serialize the request of the helper: {e}"))),
+    };
+    line.push('\n');
+    if let Err(e) = socket.write_all(line.as_bytes()).await {
+        return Err(Errcode::IoError("Can not send the request to the helper".to_string(), e));
+    }
+    Ok((helper, BufReader::new(socket).lines()))
+}
+
+async fn next_message(messages: &mut Messages) -> Result<HelperMessage, Errcode> {
+    match messages.next_line().await {
+        Ok(Some(line)) => match serde_json::from_str(&line) {
+            Ok(message) => Ok(message),
+            Err(e) => Err(Errcode::SocketError(format!("Invalid message from the helper: {e}"))),
+        },
+        Ok(None) => Err(Errcode::ContainerError("The orjail helper exited without reporting".to_string())),
+        Err(e) => Err(Errcode::IoError("Can not read from the helper".to_string(), e)),
+    }
+}
+
+fn forward_event(message: HelperMessage, events: &UnboundedSender<JailEvent>) {
+    let event = match message {
+        HelperMessage::NamespacesReady => JailEvent::NamespacesReady,
+        HelperMessage::NetworkReady => JailEvent::NetworkReady,
+        HelperMessage::Exec => JailEvent::Exec,
+        message => {
+            log::warn!("Unexpected message from the helper: {:?}", message);
+            return;
+        }
+    };
+    let _ = events.send(event);
+}
@@ -0,0 +1,160 @@
+// Helper process of spawn_async: a tokio runtime is multithreaded, and the
+// child cloned from it could block on a lock held by another thread, as clone
+// does not run the atfork handlers. The runtime executes its own binary again
+// as a helper instead, which sets the jail up from a single thread, runs it
+// and reports on a socket passed as HELPER_FD.
+use crate::errors::Errcode;
+use crate::events::JailEvent;
+use crate::exec::exec;
+use crate::jail::JailBuilder;
+use crate::state::JailState;
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::getpid;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{exit, ExitStatus};
+use std::thread;
+
+pub const HELPER_ENV: &str = "ORJAIL_HELPER";
+pub const HELPER_FD: i32 = 3;
+
+#[derive(Deserialize, Serialize)]
+pub enum HelperRequest {
+    Spawn(Box<JailBuilder>),
+    Exec { id: String, command: Vec<String> },
+}
+
+// One JSON line each, Started or Failed ends the setup, Exited the jail
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HelperMessage {
+    NamespacesReady,
+    NetworkReady,
+    Exec,
+    Started(Box<JailState>),
+    Failed(Errcode),
+    // Raw status of wait(2)
+    Exited(i32),
+}
+
+// Must be called first in main by the programs that use spawn_async, before
+// the tokio runtime or any other thread is started. In a helper process it
+// does the work of the helper and exits, otherwise it returns right away.
+pub fn run_helper() {
+    if env::var_os(HELPER_ENV).is_none() {
+        return;
+    }
+    let socket = unsafe { UnixStream::from_raw_fd(HELPER_FD) };
+    match serve(socket) {
+        Ok(_) => exit(0),
+        Err(e) => {
+            log::error!("orjail helper failed: {}", e);
+            exit(e.get_retcode());
+        }
+    }
+}
+
+fn serve(socket: UnixStream) -> Result<(), Errcode> {
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(&socket).read_line(&mut line) {
+        return Err(Errcode::IoError("Can not read the request of the helper".to_string(), e));
+    }
+    let request = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => return Err(Errcode::SocketError(format!("Invalid request for the helper: {e}"))),
+    };
+    match request {
+        HelperRequest::Spawn(builder) => spawn(*builder, socket),
+        HelperRequest::Exec { id, command } => match exec(&id, &command) {
+            Ok(code) => send(&socket, &HelperMessage::Exited(ExitStatus::from_raw((code & 0xff) << 8).into_raw())),
+            Err(e) => send(&socket, &HelperMessage::Failed(e)),
+        },
+    }
+}
+
+fn spawn(mut builder: JailBuilder, socket: UnixStream) -> Result<(), Errcode> {
+    let events = clone_socket(&socket)?;
+    builder.events = Some(Box::new(move |event| {
+        let message = match event {
+            JailEvent::NamespacesReady => HelperMessage::NamespacesReady,
+            JailEvent::NetworkReady => HelperMessage::NetworkReady,
+            JailEvent::Exec => HelperMessage::Exec,
+            _ => return,
+        };
+        if let Err(e) = send(&events, &message) {
+            log::warn!("{}", e);
+        }
+    }));
+    // The helper has its own process group, the signals it gets come from
+    // the runtime, or from the watch below once the runtime is gone
+    builder.forward_signals = true;
+
+    let mut jail = match builder.spawn() {
+        Ok(jail) => jail,
+        Err(e) => return send(&socket, &HelperMessage::Failed(e)),
+    };
+    match jail.state() {
+        Some(state) => send(&socket, &HelperMessage::Started(Box::new(state.clone())))?,
+        None => return send(&socket, &HelperMessage::Failed(Errcode::ContainerError("The jail is not running".to_string()))),
+    }
+
+    // Threads are safe once the jail is created. The runtime closes the
+    // socket when the JailHandle is dropped or the process dies, the jail
+    // is then stopped as by a SIGTERM.
+    let mut watch = clone_socket(&socket)?;
+    thread::spawn(move || {
+        let mut buf = [0; 1];
+        let _ = watch.read(&mut buf);
+        let _ = kill(getpid(), Signal::SIGTERM);
+    });
+
+    // waitpid only fails once the child is gone, it is then reported as
+    // killed
+    let status = match jail.wait_child() {
+        Ok(status) => exit_status(status),
+        Err(e) => {
+            log::error!("Error while waiting for jail {}: {}", jail.id(), e);
+            ExitStatus::from_raw(Signal::SIGKILL as i32)
+        }
+    };
+    if let Err(e) = jail.join() {
+        log::error!("Error while stopping jail {}: {}", jail.id(), e);
+    }
+    if let Err(e) = jail.clean() {
+        log::error!("Error while cleaning up jail {}: {}", jail.id(), e);
+    }
+    send(&socket, &HelperMessage::Exited(status.into_raw()))
+}
+
+fn clone_socket(socket: &UnixStream) -> Result<UnixStream, Errcode> {
+    match socket.try_clone() {
+        Ok(socket) => Ok(socket),
+        Err(e) => Err(Errcode::IoError("Can not duplicate the helper socket".to_string(), e)),
+    }
+}
+
+fn send(mut socket: &UnixStream, message: &HelperMessage) -> Result<(), Errcode> {
+    let mut line = match serde_json::to_string(message) {
+        Ok(line) => line,
+        Err(e) => return Err(Errcode::SocketError(format!("Can not serialize {:?}: {e}", message))),
+    };
+    line.push('\n');
+    match socket.write_all(line.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Errcode::IoError(format!("Can not send {:?} from the helper", message), e)),
+    }
+}
+
+// Back to the raw status of wait(2)
+fn exit_status(status: WaitStatus) -> ExitStatus {
+    match status {
+        WaitStatus::Exited(_, code) => ExitStatus::from_raw((code & 0xff) << 8),
+        WaitStatus::Signaled(_, signal, core_dumped) => ExitStatus::from_raw(signal as i32 | if core_dumped { 0x80 } else { 0 }),
+        _ => ExitStatus::from_raw(0),
+    }
+}
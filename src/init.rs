@@ -0,0 +1,98 @@
+// Minimal init kept as PID 1 of the jail with --init: the command runs in a
+// child, signals sent to the jail are forwarded to it and orphans are reaped
+use crate::errors::Errcode;
+
+use capctl::prctl;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, pthread_sigmask, SigSet, SigmaskHow, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, fork, getpid, setpgid, tcsetpgrp, ForkResult, Pid};
+use std::os::unix::io::RawFd;
+
+const FORWARDED_SIGNALS: [Signal; 4] = [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP, Signal::SIGWINCH];
+
+// Returns None in the process that goes on to run the command, and the exit
+// code of the jail in the init once the command has exited. The init closes
+// its end of the socketpair with the parent, so that the parent sees it
+// closed if the command dies before the end of the setup.
+pub fn start_init(tty: Option<RawFd>, socket: RawFd) -> Result<Option<isize>, Errcode> {
+    let mut mask = SigSet::empty();
+    for signal in FORWARDED_SIGNALS.iter().chain([Signal::SIGCHLD].iter()) {
+        mask.add(*signal);
+    }
+    // Blocked before the fork, so that nothing is lost before the signalfd
+    // of the init exists
+    let mut previous = SigSet::empty();
+    if let Err(e) = pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut previous)) {
+        return Err(Errcode::SysError("Can not block signals in init".to_string(), e));
+    }
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            // The command gets its own process group, in the foreground of
+            // the terminal, so that keys like Ctrl-C reach it only once
+            if let Err(e) = setpgid(Pid::from_raw(0), Pid::from_raw(0)) {
+                return Err(Errcode::SysError("Can not create process group".to_string(), e));
+            }
+            if let Some(tty) = tty {
+                // SIGTTOU is not sent as long as the signals are blocked
+                if let Err(e) = tcsetpgrp(tty, getpid()) {
+                    return Err(Errcode::SysError("Can not set foreground process group".to_string(), e));
+                }
+            }
+            if let Err(e) = pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&previous), None) {
+                return Err(Errcode::SysError("Can not restore signal mask".to_string(), e));
+            }
+            Ok(None)
+        }
+        Ok(ForkResult::Parent { child }) => {
+            log::debug!("Init started, command PID in the jail: {}", child);
+            // The init runs without the seccomp filter of the command and as
+            // its user: not dumpable, the command can not ptrace it nor write
+            // its memory through /proc/1/mem
+            if let Err(e) = prctl::set_dumpable(false) {
+                let _ = kill(child, Signal::SIGKILL);
+                return Err(Errcode::SysError("Can not make init non dumpable".to_string(), Errno::from_i32(e.code())));
+            }
+            let _ = close(socket);
+            let signals = match SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC) {
+                Ok(fd) => fd,
+                Err(e) => return Err(Errcode::SysError("Can not create signalfd in init".to_string(), e)),
+            };
+            run_init(child, signals).map(Some)
+        }
+        Err(e) => Err(Errcode::SysError("Can not fork the command from init".to_string(), e)),
+    }
+}
+
+fn run_init(command: Pid, mut signals: SignalFd) -> Result<isize, Errcode> {
+    loop {
+        let signal = match signals.read_signal() {
+            Ok(Some(info)) => info.ssi_signo as i32,
+            Ok(None) | Err(Errno::EINTR) => continue,
+            Err(e) => return Err(Errcode::SysError("Can not read signals in init".to_string(), e)),
+        };
+
+        if signal != Signal::SIGCHLD as i32 {
+            if let Ok(signal) = Signal::try_from(signal) {
+                log::debug!("Init forwarding {} to {}", signal, command);
+                if let Err(e) = kill(command, signal) {
+                    log::warn!("Can not forward {} to the command: {}", signal, e);
+                }
+            }
+            continue;
+        }
+
+        // A single SIGCHLD can stand for several children
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) if pid == command => return Ok(code as isize),
+                Ok(WaitStatus::Signaled(pid, signal, _)) if pid == command => return Ok(128 + signal as isize),
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+                Ok(_) => continue,
+                Err(e) => return Err(Errcode::SysError("Can not reap children in init".to_string(), e)),
+            }
+        }
+    }
+}
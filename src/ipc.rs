@@ -1,8 +1,11 @@
-#![allow(dead_code)]
 use crate::errors::Errcode;
 
-use std::os::unix::io::{AsRawFd, OwnedFd};
-use nix::sys::socket::{socketpair, AddressFamily, SockType, SockFlag, send, MsgFlags, recv};
+use serde::{Deserialize, Serialize};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use nix::cmsg_space;
+use nix::sys::socket::{socketpair, AddressFamily, SockType, SockFlag, MsgFlags, recv};
+use nix::sys::socket::{sendmsg, recvmsg, ControlMessage, ControlMessageOwned};
 
 pub fn generate_socketpair() -> Result<(OwnedFd, OwnedFd), Errcode> {
     match socketpair(
@@ -12,42 +15,79 @@ pub fn generate_socketpair() -> Result<(OwnedFd, OwnedFd), Errcode> {
         SockFlag::SOCK_CLOEXEC)
         {
             Ok(res) => Ok(res),
-            Err(e) => Err(Errcode::SocketError(format!("Can not generate socket pair: {}", e)))
+            Err(e) => Err(Errcode::SysError("Can not generate socket pair".to_string(), e))
     }
 }
 
-pub fn send_boolean(fd: OwnedFd, boolean: bool) -> Result<(), Errcode> {
-    let data: [u8; 1] = [boolean.into()];
-    let raw_fd = fd.as_raw_fd();
-    if let Err(e) = send(raw_fd, &data, MsgFlags::empty()) {
-        return Err(Errcode::SocketError(format!("Can not send {} to fd {}: {}", boolean, raw_fd.to_be(), e)));
+// Setup of a jail, in order. Each side waits for the previous stage of the
+// other one over the socketpair, nothing relies on timing.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetupMessage {
+    // Child: its namespaces exist, the parent can write its uid/gid maps
+    NamespacesCreated,
+    // Parent: maps are written and slirp4netns has configured tap0
+    NetworkReady,
+    // Child: the jail is set up up to the syscall filter, the command is
+    // about to run
+    SetupDone,
+    // Child: the setup failed, the child exits
+    Failed(Errcode),
+}
+
+// Largest serialized message, a socket of type SeqPacket would truncate it
+const MESSAGE_MAX_SIZE: usize = 64 * 1024;
+
+// Sent with sendmsg, which the syscall filter allows on the socketpair
+pub fn send_message(fd: RawFd, message: &SetupMessage) -> Result<(), Errcode> {
+    let data = match serde_json::to_vec(message) {
+        Ok(data) => data,
+        Err(e) => return Err(Errcode::SocketError(format!("Can not serialize {:?}: {}", message, e))),
     };
+    if let Err(e) = sendmsg::<()>(fd, &[IoSlice::new(&data)], &[], MsgFlags::empty(), None) {
+        return Err(Errcode::SysError(format!("Can not send {:?} to fd {}", message, fd), e));
+    }
     Ok(())
 }
 
-pub fn recv_boolean(fd: OwnedFd) -> Result<bool, Errcode> {
-    let mut data: [u8; 1] = [0];
-    let raw_fd = fd.as_raw_fd();
-    if let Err(e) = recv(raw_fd, &mut data, MsgFlags::empty()) {
-        return Err(Errcode::SocketError(format!("Can not read value from fd {}: {}", raw_fd.to_be(), e)));
+// Fails once the other side exited, its end of the socketpair is closed
+pub fn recv_message(fd: RawFd) -> Result<SetupMessage, Errcode> {
+    let mut data = vec![0; MESSAGE_MAX_SIZE];
+    let size = match recv(fd, &mut data, MsgFlags::empty()) {
+        Ok(0) => return Err(Errcode::SocketError(format!("Connection closed on fd {}", fd))),
+        Ok(size) => size,
+        Err(e) => return Err(Errcode::SysError(format!("Can not read message from fd {}", fd), e)),
+    };
+    match serde_json::from_slice(&data[..size]) {
+        Ok(message) => Ok(message),
+        Err(e) => Err(Errcode::SocketError(format!("Invalid message on fd {}: {}", fd, e))),
     }
-    Ok(data[0] == 1)
 }
 
-pub fn send_u32(fd: OwnedFd, value: u32) -> Result<(), Errcode> {
-    let data: &[u8; 4] = &value.to_be_bytes();
-    let raw_fd = fd.as_raw_fd();
-    if let Err(e) = send(raw_fd, data, MsgFlags::empty()) {
-        return Err(Errcode::SocketError(format!("Can not send {} to fd {}: {}", value, raw_fd.to_be(), e)));
-    };
+pub fn send_fd(fd: RawFd, sent_fd: RawFd) -> Result<(), Errcode> {
+    // A single byte of data carries the file descriptor as SCM_RIGHTS
+    let data: [u8; 1] = [1];
+    let fds = [sent_fd];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    if let Err(e) = sendmsg::<()>(fd, &[IoSlice::new(&data)], &cmsg, MsgFlags::empty(), None) {
+        return Err(Errcode::SysError(format!("Can not send fd {} to fd {}", sent_fd, fd), e));
+    }
     Ok(())
 }
 
-pub fn recv_u32(fd: OwnedFd) -> Result<u32, Errcode> {
-    let mut data: [u8; 4] = [0; 4];
-    let raw_fd = fd.as_raw_fd();
-    if let Err(e) = recv(raw_fd, &mut data, MsgFlags::empty()) {
-        return Err(Errcode::SocketError(format!("Can not read value from fd {}: {}", raw_fd.to_be(), e)));
+pub fn recv_fd(fd: RawFd) -> Result<OwnedFd, Errcode> {
+    let mut data: [u8; 1] = [0];
+    let mut iov = [IoSliceMut::new(&mut data)];
+    let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+    let msg = match recvmsg::<()>(fd, &mut iov, Some(&mut cmsg_buffer), MsgFlags::MSG_CMSG_CLOEXEC) {
+        Ok(msg) => msg,
+        Err(e) => return Err(Errcode::SysError(format!("Can not read fd from fd {}", fd), e)),
+    };
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(received) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(*received) });
+            }
+        }
     }
-    Ok(u32::from_be_bytes(data))
+    Err(Errcode::SocketError(format!("No fd received from fd {}", fd)))
 }
@@ -0,0 +1,418 @@
+// Library interface of orjail: a JailBuilder describes the jail, spawn sets
+// it up and returns the Jail handle of the running jail. The orjailrs command
+// line is a frontend to it.
+use crate::audit::{write_suggestion, AuditRecord};
+use crate::container::{check_compatibility, Container};
+use crate::detach::{daemonize, Detached};
+use crate::errors::Errcode;
+use crate::events::EventSender;
+use crate::exec::exec;
+use crate::logs::RotatingLog;
+use crate::mountspec::MountSpec;
+use crate::nftables::NAT_TABLE;
+use crate::profile::Profile;
+use crate::resources::ResourceLimits;
+use crate::pty::{start_log_relay, start_relay, TerminalGuard};
+use crate::seccomp::SeccompProfile;
+use crate::signals::ParentSignals;
+use crate::state::{now, JailState, StateDir, ATTACH_SOCKET};
+use crate::supervisor::start_supervisor;
+use crate::tor::newnym;
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+
+// Serialized for the helper process of spawn_async
+#[derive(Deserialize, Serialize)]
+pub struct JailBuilder {
+    pub(crate) command: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) uid: u32,
+    pub(crate) mounts: Vec<MountSpec>,
+    pub(crate) mount_dir: Option<PathBuf>,
+    pub(crate) minimal_root: bool,
+    // Overlay on the home of the user, with the name of the directory keeping
+    // its writes when persistent
+    pub(crate) home: Option<Option<String>>,
+    // The hostname of the jail when not set
+    pub(crate) namespace: Option<String>,
+    pub(crate) slirp4netns: Option<PathBuf>,
+    pub(crate) tor: Option<PathBuf>,
+    pub(crate) profile: Option<Profile>,
+    pub(crate) seccomp_profile: Option<SeccompProfile>,
+    pub(crate) seccomp_audit: bool,
+    pub(crate) seccomp_audit_output: Option<PathBuf>,
+    pub(crate) disable_syscall: bool,
+    pub(crate) disable_capabilities: bool,
+    pub(crate) init: bool,
+    pub(crate) detach: bool,
+    pub(crate) forward_signals: bool,
+    pub(crate) limits: ResourceLimits,
+    #[serde(skip)]
+    pub(crate) events: Option<EventSender>,
+}
+
+impl JailBuilder {
+    // The first element of command is the path of the program, it is not
+    // looked up in PATH
+    pub fn new<I, S>(command: I) -> JailBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        JailBuilder {
+            command: command.into_iter().map(Into::into).collect(),
+            env: vec![],
+            uid: 0,
+            mounts: vec![],
+            mount_dir: None,
+            minimal_root: false,
+            home: None,
+            namespace: None,
+            slirp4netns: None,
+            tor: None,
+            profile: None,
+            seccomp_profile: None,
+            seccomp_audit: false,
+            seccomp_audit_output: None,
+            disable_syscall: false,
+            disable_capabilities: false,
+            init: false,
+            detach: false,
+            forward_signals: false,
+            limits: ResourceLimits::default(),
+            events: None,
+        }
+    }
+
+    // The command starts with an empty environment
+    pub fn env(mut self, key: &str, value: &str) -> JailBuilder {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn uid(mut self, uid: u32) -> JailBuilder {
+        self.uid = uid;
+        self
+    }
+
+    // Applied with root_dir or minimal_root only
+    pub fn mount(mut self, spec: MountSpec) -> JailBuilder {
+        self.mounts.push(spec);
+        self
+    }
+
+    pub fn root_dir(mut self, path: PathBuf) -> JailBuilder {
+        self.mount_dir = Some(path);
+        self
+    }
+
+    pub fn minimal_root(mut self, minimal_root: bool) -> JailBuilder {
+        self.minimal_root = minimal_root;
+        self
+    }
+
+    // Writes are discarded on exit, or kept in
+    // ~/.local/share/orjail/homes/<persist> by a home that starts empty
+    pub fn home_overlay(mut self, persist: Option<&str>) -> JailBuilder {
+        self.home = Some(persist.map(str::to_string));
+        self
+    }
+
+    // Name of the network namespace of the jail, defaults to its hostname
+    pub fn network(mut self, namespace: &str) -> JailBuilder {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    // Looked up in PATH when not set
+    pub fn slirp4netns(mut self, path: PathBuf) -> JailBuilder {
+        self.slirp4netns = Some(path);
+        self
+    }
+
+    // Looked up in PATH when not set
+    pub fn tor(mut self, path: PathBuf) -> JailBuilder {
+        self.tor = Some(path);
+        self
+    }
+
+    // Defaults to the built-in default profile
+    pub fn profile(mut self, profile: Profile) -> JailBuilder {
+        self.profile = Some(profile);
+        self
+    }
+
+    // Defaults to the seccomp profile named by the security profile
+    pub fn seccomp_profile(mut self, profile: SeccompProfile) -> JailBuilder {
+        self.seccomp_profile = Some(profile);
+        self
+    }
+
+    // The suggested profile is written to output, or to stderr, by wait
+    pub fn seccomp_audit(mut self, output: Option<PathBuf>) -> JailBuilder {
+        self.seccomp_audit = true;
+        self.seccomp_audit_output = output;
+        self
+    }
+
+    pub fn disable_syscall(mut self, disable: bool) -> JailBuilder {
+        self.disable_syscall = disable;
+        self
+    }
+
+    pub fn disable_capabilities(mut self, disable: bool) -> JailBuilder {
+        self.disable_capabilities = disable;
+        self
+    }
+
+    pub fn init(mut self, init: bool) -> JailBuilder {
+        self.init = init;
+        self
+    }
+
+    // The calling process forks: the foreground one prints the ID of the jail
+    // and exits once it is ready, spawn returns in the background one
+    pub fn detach(mut self, detach: bool) -> JailBuilder {
+        self.detach = detach;
+        self
+    }
+
+    // Termination signals of the calling process are blocked and forwarded
+    // to the jail by wait, as the orjailrs command does. Must be set before
+    // the calling process starts any thread.
+    pub fn forward_signals(mut self, forward: bool) -> JailBuilder {
+        self.forward_signals = forward;
+        self
+    }
+
+    // cgroup v2 limits, in a subtree delegated to the user by systemd
+    pub fn limits(mut self, limits: ResourceLimits) -> JailBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn spawn(self) -> Result<Jail, Errcode> {
+        check_compatibility()?;
+
+        let detached = if self.detach { Some(daemonize()?) } else { None };
+        // Blocked before the child is created, so that a signal during the
+        // setup does not leave it behind: the setup is rolled back and the
+        // jail cleaned up. Before any thread is spawned, so that they all
+        // leave the signals to the signalfd.
+        let signals = if self.forward_signals { Some(ParentSignals::block()?) } else { None };
+        let mut container = Container::new(self)?;
+        container.config.sigmask = signals.as_ref().map(ParentSignals::previous);
+        container.config.ready_fd = detached.as_ref().map(Detached::fd);
+        if let Err(e) = container.create() {
+            log::error!("Error while creating container: {:?}", e);
+            container.config.slirp_process = None;
+            let _ = container.clean_exit();
+            return Err(e);
+        }
+
+        let child = match container.child.take() {
+            Some(child) => child,
+            None => return Err(Errcode::ContainerError("The container was not created".to_string())),
+        };
+        let mut jail = Jail {
+            container,
+            child,
+            running: true,
+            cleaned: false,
+            state: None,
+            state_dir: None,
+            signals,
+            _terminal: None,
+            relay: None,
+            supervisor: None,
+        };
+        // Dropping the jail on error kills and cleans it up
+        jail.start(detached)?;
+        Ok(jail)
+    }
+}
+
+// Handle of a running jail. Dropping it kills the jail and cleans it up,
+// wait lets it run to its end.
+pub struct Jail {
+    container: Container,
+    child: Pid,
+    running: bool,
+    cleaned: bool,
+    state: Option<JailState>,
+    state_dir: Option<StateDir>,
+    signals: Option<ParentSignals>,
+    // Puts the terminal back once dropped
+    _terminal: Option<TerminalGuard>,
+    relay: Option<JoinHandle<()>>,
+    supervisor: Option<JoinHandle<Vec<AuditRecord>>>,
+}
+
+impl Jail {
+    fn start(&mut self, detached: Option<Detached>) -> Result<(), Errcode> {
+        let config = &self.container.config;
+        let state = JailState {
+            id: config.hostname.clone(),
+            command: config.argv.iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>().join(" "),
+            started: now(),
+            pid: std::process::id() as i32,
+            child_pid: self.child.as_raw(),
+            slirp_pid: config.slirp_process.as_ref().and_then(|slirp| slirp.lock().ok().map(|slirp| slirp.process.id())),
+            namespace: config.namespace.clone(),
+            nftables_table: NAT_TABLE.to_string(),
+            root: config.root.clone(),
+            tor_data: PathBuf::from(format!("/tmp/{}/lib/tor", config.hostname)),
+            profile: config.profile.clone(),
+            seccomp_profile: config.seccomp_profile.clone(),
+            disable_syscall: config.disable_syscall,
+            disable_capabilities: config.disable_capabilities,
+            cgroup: self.container.cgroup.as_ref().map(|cgroup| cgroup.path().to_path_buf()),
+        };
+        let state_dir = self.state_dir.insert(state.save()?);
+        self.state = Some(state);
+
+        match (self.container.pty_master.take(), detached) {
+            (Some(master), Some(detached)) => {
+                let log = RotatingLog::open(state_dir.path())?;
+                let socket_path = state_dir.path().join(ATTACH_SOCKET);
+                let listener = match UnixListener::bind(&socket_path) {
+                    Ok(listener) => listener,
+                    Err(e) => return Err(Errcode::IoError(format!("Can not create {}", socket_path.display()), e)),
+                };
+                self.relay = Some(start_log_relay(master, log, listener));
+                detached.ready(&self.container.config.hostname, state_dir.path())?;
+            }
+            (Some(master), None) => {
+                let (guard, handle) = start_relay(master, false)?;
+                self._terminal = Some(guard);
+                self.relay = Some(handle);
+            }
+            (None, Some(detached)) => {
+                log::warn!("Without a terminal the output of the detached jail is not logged");
+                detached.ready(&self.container.config.hostname, state_dir.path())?;
+            }
+            (None, None) => {},
+        }
+
+        let config = &self.container.config;
+        if !config.disable_syscall && (config.seccomp_audit || config.profile.supervisor.enabled()) {
+            self.supervisor = Some(start_supervisor(self.container.socket.as_raw_fd(), config.profile.supervisor.clone(), config.seccomp_audit));
+        }
+        Ok(())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.container.config.hostname
+    }
+
+    // Also read by orjailrs list from the state directory of the jail
+    pub fn state(&self) -> Option<&JailState> {
+        self.state.as_ref()
+    }
+
+    // Without --init, the command is PID 1 of the jail and only gets the
+    // signals it handles
+    pub fn kill(&self, signal: Signal) -> Result<(), Errcode> {
+        match kill(self.child, signal) {
+            Ok(_) | Err(Errno::ESRCH) => Ok(()),
+            Err(e) => Err(Errcode::SysError(format!("Can not send {} to the jail", signal), e)),
+        }
+    }
+
+    // Runs command in the jail and waits for it, see orjailrs exec
+    pub fn exec(&self, command: &[String]) -> Result<i32, Errcode> {
+        exec(self.id(), command)
+    }
+
+    // New TOR circuits for the connections opened from now on
+    pub fn newnym(&self) -> Result<(), Errcode> {
+        match self.state.as_ref() {
+            Some(state) => newnym(&state.tor_data),
+            None => Err(Errcode::TorError("The jail is not running".to_string())),
+        }
+    }
+
+    // Waits for the command of the jail to exit and cleans the jail up,
+    // returns its exit code or 128 + the signal that killed it
+    pub fn wait(mut self) -> Result<i32, Errcode> {
+        let result = self.wait_child().and_then(|status| {
+            self.join()?;
+            Ok(match status {
+                WaitStatus::Exited(_, code) => code,
+                WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+                _ => 0,
+            })
+        });
+        let cleaned = self.clean();
+        result.and_then(|code| cleaned.map(|_| code))
+    }
+
+    pub(crate) fn wait_child(&mut self) -> Result<WaitStatus, Errcode> {
+        let status = match self.signals.as_mut() {
+            Some(signals) => signals.wait_child(self.child)?,
+            None => loop {
+                match waitpid(self.child, None) {
+                    Ok(status) => break status,
+                    Err(Errno::EINTR) => continue,
+                    Err(e) => return Err(Errcode::SysError("Error while waiting for child to finish".to_string(), e)),
+                }
+            },
+        };
+        self.running = false;
+        log::debug!("Finished with {:?}, cleaning & exit", status);
+        Ok(status)
+    }
+
+    pub(crate) fn join(&mut self) -> Result<(), Errcode> {
+        // The pty hangs up once every process of the container is gone
+        if let Some(handle) = self.relay.take() {
+            if handle.join().is_err() {
+                log::error!("Terminal relay thread panicked");
+            }
+        }
+
+        if let Some(handle) = self.supervisor.take() {
+            let config = &self.container.config;
+            match handle.join() {
+                Ok(records) if config.seccomp_audit => {
+                    write_suggestion(&config.seccomp_profile, &records, &config.seccomp_audit_output)?
+                }
+                Ok(_) => {},
+                Err(_) => log::error!("Seccomp supervisor thread panicked"),
+            }
+        }
+        Ok(())
+    }
+
+    // Whatever happened before, nothing of the jail is left behind
+    pub(crate) fn clean(&mut self) -> Result<(), Errcode> {
+        if self.cleaned {
+            return Ok(());
+        }
+        self.cleaned = true;
+        if self.running {
+            let _ = kill(self.child, Signal::SIGKILL);
+            let _ = waitpid(self.child, None);
+            self.running = false;
+        }
+        self.container.config.slirp_process = None;
+        self.state_dir = None;
+        self.container.clean_exit()
+    }
+}
+
+impl Drop for Jail {
+    fn drop(&mut self) {
+        if let Err(e) = self.clean() {
+            log::error!("Error while cleaning up jail {}: {}", self.id(), e);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Landlock filesystem sandbox: outside of the listed paths the jail can not
+// read, write or execute anything, whatever the mounts it can see
+use crate::errors::Errcode;
+
+use capctl::prctl;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use std::os::unix::io::{FromRawFd, OwnedFd, AsRawFd};
+use std::path::{Path, PathBuf};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+// ABI 1 handles every right up to MAKE_SYM, later ABIs add the others
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_READ_EXEC: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+// The only rights that apply to a path that is not a directory
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV;
+
+const SYSTEM_PATHS: [&str; 10] = ["/bin", "/sbin", "/usr", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/proc", "/dev/urandom"];
+const WRITABLE_PATHS: [&str; 7] = ["/tmp", "/dev/null", "/dev/zero", "/dev/full", "/dev/tty", "/dev/pts", "/dev/ptmx"];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LandlockPolicy {
+    pub enabled: bool,
+    // Readable and executable, in addition to the system directories
+    pub read_only: Vec<PathBuf>,
+    // Fully accessible, in addition to the home and /tmp
+    pub read_write: Vec<PathBuf>,
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// Restrict the filesystem of the jail to the system directories, read only,
+// and to the home of the user, /tmp and the allowed devices. Kernels without
+// Landlock only get a warning, the mount namespace stays the only restriction
+// there.
+pub fn setlandlock(policy: &LandlockPolicy, uid: u32, devices: &[PathBuf]) -> Result<(), Errcode> {
+    let abi = match landlock_abi() {
+        Some(abi) => abi,
+        None => {
+            log::warn!("Landlock is not supported or disabled by this kernel, the filesystem of the jail is not restricted");
+            return Ok(());
+        }
+    };
+    let handled = handled_access(abi);
+    if abi < 3 {
+        log::warn!("Landlock ABI {} does not restrict file renaming and truncation, a kernel with ABI 3 or later is recommended", abi);
+    }
+    log::debug!("Restricting filesystem access with Landlock ABI {}", abi);
+
+    let attr = RulesetAttr { handled_access_fs: handled };
+    let res = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0)
+    };
+    if res < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Errcode::IoError("Can not create Landlock ruleset".to_string(), e));
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(res as i32) };
+
+    let mut read_only: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+    read_only.extend(policy.read_only.iter().cloned());
+    let mut read_write: Vec<PathBuf> = WRITABLE_PATHS.iter().map(PathBuf::from).collect();
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => read_write.push(user.dir),
+        _ => log::warn!("Can not find the home of user {}, it is not accessible in the jail", uid),
+    }
+    read_write.extend(policy.read_write.iter().cloned());
+    read_write.extend(devices.iter().cloned());
+
+    for path in read_only.iter() {
+        add_path_rule(&ruleset, path, ACCESS_READ_EXEC & handled)?;
+    }
+    for path in read_write.iter() {
+        add_path_rule(&ruleset, path, handled)?;
+    }
+
+    // Needed without CAP_SYS_ADMIN, capabilities may already have set it
+    if let Err(e) = prctl::set_no_new_privs() {
+        return Err(Errcode::SysError("Can not set no_new_privs".to_string(), Errno::from_i32(e.code())));
+    }
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Errcode::IoError("Can not enforce Landlock ruleset".to_string(), e));
+    }
+    Ok(())
+}
+
+fn landlock_abi() -> Option<i64> {
+    let abi = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+    };
+    if abi < 1 {
+        None
+    } else {
+        Some(abi)
+    }
+}
+
+fn handled_access(abi: i64) -> u64 {
+    let mut handled = ACCESS_FS_ABI_1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= ACCESS_FS_IOCTL_DEV;
+    }
+    handled
+}
+
+// Paths that do not exist on this system are skipped
+fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<(), Errcode> {
+    let fd = match open(path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+        Err(e) => {
+            log::debug!("Skipping Landlock rule for {}: {}", path.display(), e);
+            return Ok(());
+        }
+    };
+    let access = if path.is_dir() { access } else { access & ACCESS_FILE };
+
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd.as_raw_fd(),
+    };
+    let res = unsafe {
+        libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0)
+    };
+    if res < 0 {
+        let e = std::io::Error::last_os_error();
+        log::error!("Can not add Landlock rule for {}: {}", path.display(), e);
+        return Err(Errcode::IoError(format!("Can not add rule for {}", path.display()), e));
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// orjail runs a command in a rootless jail whose traffic can only go through
+// TOR. JailBuilder sets up a jail and returns its Jail handle, the other
+// functions act on the running jails by ID, as the orjailrs subcommands do.
+mod audit;
+mod capabilities;
+mod child;
+mod config;
+mod container;
+mod detach;
+mod errors;
+mod events;
+mod exec;
+mod handle;
+mod helper;
+mod hostname;
+mod init;
+mod ipc;
+mod jail;
+mod landlock;
+mod lifecycle;
+mod logs;
+mod mountpoint;
+mod mountspec;
+mod namespaces;
+mod net;
+mod nftables;
+mod profile;
+mod pty;
+mod resources;
+mod rollback;
+mod seccomp;
+mod signals;
+mod slirp;
+mod state;
+mod supervisor;
+mod syscalls;
+mod tor;
+mod utils;
+
+pub use detach::attach;
+pub use errors::{Errcode, SetupStage};
+pub use events::{JailEvent, JailEvents};
+pub use exec::exec;
+pub use handle::JailHandle;
+pub use helper::run_helper;
+pub use jail::{Jail, JailBuilder};
+pub use lifecycle::{gc, stop};
+pub use logs::logs;
+pub use mountspec::MountSpec;
+pub use nix::sys::signal::Signal;
+pub use profile::Profile;
+pub use resources::ResourceLimits;
+pub use seccomp::SeccompProfile;
+pub use state::{list, JailState};
@@ -0,0 +1,245 @@
+// orjailrs stop and gc: end running jails, and remove what crashed ones left
+// behind. Veth pairs and nftables tables live in the network namespace of the
+// jail and disappear with it, the rest is on the filesystem of the host.
+use crate::errors::Errcode;
+use crate::signals::GRACE_PERIOD;
+use crate::state::{find_jail, list_jails, state_root, JailState, STATE_FILE};
+
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getuid, Pid};
+use std::fs::{read_dir, read_link, read_to_string, remove_dir, remove_dir_all, remove_file};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+// Left to orjail after the grace period of the jail, to clean it up
+const STOP_MARGIN: Duration = Duration::from_secs(5);
+// Resources younger than this may belong to a jail that is starting
+const GC_MIN_AGE: Duration = Duration::from_secs(60);
+const TOR_DATA: &str = "/tmp/tor";
+const NETNS: &str = "/run/netns";
+
+// The orjail process of the jail forwards the signal, waits for the jail and
+// cleans up, it is only killed along with the jail if that takes too long
+pub fn stop(jail: &str) -> Result<(), Errcode> {
+    let state = find_jail(jail)?;
+    log::info!("Stopping jail {}", state.id);
+    if let Err(e) = kill(Pid::from_raw(state.pid), Signal::SIGTERM) {
+        return Err(Errcode::SysError(format!("Can not signal orjail process {}", state.pid), e));
+    }
+
+    if wait_stopped(&state.id, GRACE_PERIOD + STOP_MARGIN) {
+        log::info!("Jail {} stopped", state.id);
+        return Ok(());
+    }
+    log::warn!("Jail {} did not stop, killing it, run orjailrs gc to clean it up", state.id);
+    for pid in [state.child_pid, state.pid] {
+        let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+    }
+    if !wait_stopped(&state.id, STOP_MARGIN) {
+        return Err(Errcode::ContainerError(format!("Jail {} is still running", state.id)));
+    }
+    Ok(())
+}
+
+fn wait_stopped(id: &str, timeout: Duration) -> bool {
+    let deadline = SystemTime::now() + timeout;
+    while SystemTime::now() < deadline {
+        match list_jails() {
+            Ok(jails) if !jails.iter().any(|(state, alive)| state.id == id && *alive) => return true,
+            _ => sleep(Duration::from_millis(100)),
+        }
+    }
+    false
+}
+
+pub fn gc(dry_run: bool) -> Result<(), Errcode> {
+    let jails = list_jails()?;
+    let running: Vec<&JailState> = jails.iter().filter(|(_, alive)| *alive).map(|(state, _)| state).collect();
+    let mut collector = Collector { dry_run, removed: 0 };
+
+    for (state, _) in jails.iter().filter(|(_, alive)| !*alive) {
+        collector.dead_jail(state, &running);
+    }
+
+    // Logs left by detached jails that exited
+    let state_dir = state_root();
+    if let Ok(entries) = read_dir(&state_dir) {
+        for entry in entries.flatten() {
+            if !entry.path().join(STATE_FILE).exists() && is_old(&entry.path()) {
+                collector.remove(&entry.path(), true);
+            }
+        }
+    }
+
+    // Directories of jails without a state file, found by name
+    let entries = match read_dir("/tmp") {
+        Ok(entries) => entries,
+        Err(e) => return Err(Errcode::IoError("Can not read /tmp".to_string(), e)),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if path == state_dir || !is_old(&path) || jails.iter().any(|(state, _)| owns(state, &path)) {
+            continue;
+        }
+        if is_hostname(&name) && is_netns_dir(&path) {
+            collector.remove(&path, true);
+        } else if name.starts_with("orjail.") || name.starts_with("orjail-dev.") || name.starts_with("orjail-overlay.") {
+            // Mount points in the namespace of a jail, empty on the host
+            collector.remove(&path, false);
+        }
+    }
+
+    // Shared by every jail
+    let tor = Path::new(TOR_DATA);
+    if running.is_empty() && tor.exists() && is_old(tor) {
+        collector.remove(tor, true);
+    }
+
+    match (dry_run, collector.removed) {
+        (_, 0) => println!("Nothing to clean up"),
+        (true, n) => println!("{} resources would be removed", n),
+        (false, n) => println!("{} resources removed", n),
+    }
+    Ok(())
+}
+
+struct Collector {
+    dry_run: bool,
+    removed: usize,
+}
+
+impl Collector {
+    fn dead_jail(&mut self, state: &JailState, running: &[&JailState]) {
+        log::debug!("Cleaning up dead jail {}", state.id);
+        if let Some(pid) = jail_process(state.child_pid) {
+            self.kill(pid, "jail");
+        }
+        if let Some(pid) = state.slirp_pid.and_then(|pid| process_named(pid as i32, "slirp4netns")) {
+            self.kill(pid, "slirp4netns");
+        }
+
+        let netns = Path::new(NETNS).join(&state.namespace);
+        if netns.exists() && !running.iter().any(|other| other.namespace == state.namespace) {
+            self.remove_netns(&netns);
+        }
+        let hostname_dir = PathBuf::from(format!("/tmp/{}", state.id));
+        if hostname_dir.exists() {
+            self.remove(&hostname_dir, true);
+        }
+        if let Some(root) = state.root.as_ref().filter(|root| root.exists()) {
+            self.remove(root, false);
+        }
+        // Empty once the processes of the jail are gone
+        if let Some(cgroup) = state.cgroup.as_ref().filter(|cgroup| cgroup.exists()) {
+            self.remove(cgroup, false);
+        }
+        self.remove(&state_root().join(&state.id), true);
+    }
+
+    fn kill(&mut self, pid: Pid, what: &str) {
+        if self.dry_run {
+            println!("would kill {} process {}", what, pid);
+        } else {
+            match kill(pid, Signal::SIGKILL) {
+                Ok(_) => println!("killed {} process {}", what, pid),
+                Err(e) => log::warn!("Can not kill {} process {}: {}", what, pid, e),
+            }
+        }
+        self.removed += 1;
+    }
+
+    fn remove_netns(&mut self, path: &Path) {
+        if self.dry_run {
+            println!("would remove network namespace {}", path.display());
+            self.removed += 1;
+            return;
+        }
+        match umount2(path, MntFlags::MNT_DETACH) {
+            Ok(_) | Err(Errno::EINVAL) => {},
+            Err(e) => {
+                log::warn!("Can not unmount {}: {}", path.display(), e);
+                return;
+            }
+        }
+        match remove_file(path) {
+            Ok(_) => {
+                println!("removed network namespace {}", path.display());
+                self.removed += 1;
+            }
+            Err(e) => log::warn!("Can not remove {}: {}", path.display(), e),
+        }
+    }
+
+    // Mount points are never removed recursively, whatever could still be
+    // mounted below them is left alone
+    fn remove(&mut self, path: &Path, recursive: bool) {
+        if self.dry_run {
+            println!("would remove {}", path.display());
+            self.removed += 1;
+            return;
+        }
+        let res = if recursive { remove_dir_all(path) } else { remove_dir(path) };
+        match res {
+            Ok(_) => {
+                println!("removed {}", path.display());
+                self.removed += 1;
+            }
+            Err(e) => log::warn!("Can not remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Hostnames of jails are orjail-<4 alphanumeric characters>
+fn is_hostname(name: &str) -> bool {
+    match name.strip_prefix("orjail-") {
+        Some(suffix) => suffix.len() == 4 && suffix.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => false,
+    }
+}
+
+// The network namespace directories of a jail, see mount_netns. The state
+// root of orjail-<uid> when XDG_RUNTIME_DIR is not set also looks like a
+// hostname, and holds the state of live jails.
+fn is_netns_dir(path: &Path) -> bool {
+    let state_fallback = format!("orjail-{}", getuid());
+    path.file_name().is_some_and(|name| name != state_fallback.as_str()) && path.join("netns").is_dir()
+}
+
+fn owns(state: &JailState, path: &Path) -> bool {
+    path == Path::new(&format!("/tmp/{}", state.id)) || state.root.as_deref() == Some(path)
+}
+
+fn is_old(path: &Path) -> bool {
+    match path.symlink_metadata().and_then(|meta| meta.modified()) {
+        Ok(modified) => modified.elapsed().map(|age| age > GC_MIN_AGE).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+// PIDs are checked before being killed, they may have been reused: the
+// command of a jail runs in its own PID namespace, as the same user
+fn jail_process(pid: i32) -> Option<Pid> {
+    let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+    let owner = proc_dir.metadata().ok()?.uid();
+    let own_ns = read_link("/proc/self/ns/pid").ok()?;
+    let ns = read_link(proc_dir.join("ns/pid")).ok()?;
+    if owner == getuid().as_raw() && ns != own_ns {
+        Some(Pid::from_raw(pid))
+    } else {
+        None
+    }
+}
+
+fn process_named(pid: i32, name: &str) -> Option<Pid> {
+    let comm = read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    if comm.trim() == name {
+        Some(Pid::from_raw(pid))
+    } else {
+        None
+    }
+}
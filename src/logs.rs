@@ -0,0 +1,104 @@
+// Output of detached jails, kept in their state directory. The log is rotated
+// once it reaches LOG_MAX_SIZE, the previous one is kept as output.log.1
+use crate::errors::Errcode;
+use crate::state::jail_dir;
+
+use std::fs::{rename, File, OpenOptions};
+use std::io::{copy, stdout, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+const LOG_FILE: &str = "output.log";
+const LOG_MAX_SIZE: u64 = 1 << 20;
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    pub fn open(dir: &Path) -> Result<RotatingLog, Errcode> {
+        let path = dir.join(LOG_FILE);
+        let file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(Errcode::IoError(format!("Can not open log {}", path.display()), e)),
+        };
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(RotatingLog { path, file, size })
+    }
+
+    // Errors are only logged, the jail keeps running without its output
+    pub fn write(&mut self, data: &[u8]) {
+        if self.size >= LOG_MAX_SIZE {
+            self.rotate();
+        }
+        match self.file.write_all(data) {
+            Ok(_) => self.size += data.len() as u64,
+            Err(e) => log::error!("Can not write log {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn rotate(&mut self) {
+        let previous = self.path.with_extension("log.1");
+        if let Err(e) = rename(&self.path, &previous) {
+            log::error!("Can not rotate log {}: {}", self.path.display(), e);
+            return;
+        }
+        match OpenOptions::new().create(true).append(true).open(&self.path) {
+            Ok(file) => {
+                self.file = file;
+                self.size = 0;
+            }
+            Err(e) => log::error!("Can not reopen log {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// Print the log of a jail, and with follow keep printing what it writes
+// until it exits
+pub fn logs(jail: &str, follow: bool) -> Result<(), Errcode> {
+    let (dir, alive) = jail_dir(jail)?;
+    let path = dir.join(LOG_FILE);
+    if let Ok(mut previous) = File::open(path.with_extension("log.1")) {
+        let _ = copy(&mut previous, &mut stdout());
+    }
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => return Err(Errcode::IoError(format!("No log for jail {}", jail), e)),
+    };
+    if let Err(e) = copy(&mut file, &mut stdout()) {
+        return Err(Errcode::IoError(format!("Can not read log {}", path.display()), e));
+    }
+    if !follow || !alive {
+        return Ok(());
+    }
+
+    loop {
+        sleep(FOLLOW_INTERVAL);
+        let mut buf = vec![];
+        let _ = file.read_to_end(&mut buf);
+        let _ = stdout().write_all(&buf);
+        let _ = stdout().flush();
+
+        // Once rotated, the new log is read from its start
+        let rotated = match (path.metadata(), file.metadata()) {
+            (Ok(current), Ok(opened)) => current.ino() != opened.ino(),
+            _ => false,
+        };
+        if rotated {
+            let _ = copy(&mut file, &mut stdout());
+            if let Ok(new) = File::open(&path) {
+                file = new;
+            }
+            continue;
+        }
+        if !jail_dir(jail).map(|(_, alive)| alive).unwrap_or(false) {
+            let _ = copy(&mut file, &mut stdout());
+            return Ok(());
+        }
+    }
+}
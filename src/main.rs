@@ -1,29 +1,27 @@
-mod capabilities;
-mod child;
 mod cli;
-mod config;
-mod container;
-mod errors;
-mod hostname;
-mod ipc;
-mod mountpoint;
-mod namespaces;
-mod net;
-mod nftables;
-mod resources;
-mod slirp;
-mod syscalls;
-mod tor;
-mod utils;
 
-use errors::exit_with_retcode;
+use cli::{Args, Subcommand};
+use orjail::{Errcode, JailBuilder, MountSpec, Profile, ResourceLimits, SeccompProfile};
+use std::path::PathBuf;
 use std::process::exit;
 
 fn main() {
     match cli::parse_args(){
         Ok(args) => {
             log::info!("{:?}", args);
-            exit_with_retcode(container::start(args));
+            match args.subcommand {
+                Some(Subcommand::List) => exit_with_retcode(orjail::list()),
+                // Exits with the code of the command
+                Some(Subcommand::Exec { ref jail, ref command }) => match orjail::exec(jail, command) {
+                    Ok(code) => exit(code),
+                    Err(e) => exit_with_retcode(Err(e)),
+                },
+                Some(Subcommand::Stop { ref jail }) => exit_with_retcode(orjail::stop(jail)),
+                Some(Subcommand::Logs { ref jail, follow }) => exit_with_retcode(orjail::logs(jail, follow)),
+                Some(Subcommand::Attach { ref jail }) => exit_with_retcode(orjail::attach(jail)),
+                Some(Subcommand::Gc { dry_run }) => exit_with_retcode(orjail::gc(dry_run)),
+                None => exit_with_retcode(start(args)),
+            }
         },
         Err(e) => {
             log::error!("Error while parsing arguments:\n\t{}", e);
@@ -31,3 +29,74 @@ fn main() {
         }
     };
 }
+
+fn start(args: Args) -> Result<(), Errcode> {
+    let mut jail = JailBuilder::new(args.command.split_ascii_whitespace())
+        .uid(args.uid)
+        .minimal_root(args.minimal_root)
+        .disable_syscall(args.disable_syscall)
+        .disable_capabilities(args.disable_capabilities)
+        .init(args.init)
+        .detach(args.detach)
+        .forward_signals(true)
+        .profile(Profile::load(&args.profile)?);
+
+    for spec in args.addpaths.iter() {
+        jail = jail.mount(MountSpec::parse_bind(spec)?);
+    }
+    for spec in args.tmpfs.iter() {
+        jail = jail.mount(MountSpec::parse_tmpfs(spec)?);
+    }
+    for spec in args.overlay.iter() {
+        jail = jail.mount(MountSpec::parse_overlay(spec)?);
+    }
+    if let Some(namespace) = args.namespace.as_deref() {
+        jail = jail.network(namespace);
+    }
+    if !args.mount_dir.is_empty() {
+        jail = jail.root_dir(PathBuf::from(&args.mount_dir));
+    }
+    if args.ephemeral_home || args.persist_home.is_some() {
+        jail = jail.home_overlay(args.persist_home.as_deref());
+    }
+    if !args.tor.is_empty() {
+        jail = jail.tor(PathBuf::from(&args.tor));
+    }
+    if !args.slirp4netns.is_empty() {
+        jail = jail.slirp4netns(PathBuf::from(&args.slirp4netns));
+    }
+    if !args.seccomp_profile.is_empty() {
+        jail = jail.seccomp_profile(SeccompProfile::load(&args.seccomp_profile)?);
+    }
+    let limits = ResourceLimits {
+        memory_max: args.memory_max.as_deref().map(ResourceLimits::parse_size).transpose()?,
+        pids_max: args.pids_max,
+        cpu_max: args.cpu_max.as_deref().map(ResourceLimits::parse_cpu_max).transpose()?,
+        io_weight: args.io_weight,
+    };
+    jail = jail.limits(limits);
+    if args.seccomp_audit {
+        jail = jail.seccomp_audit(args.seccomp_audit_output);
+    }
+
+    // orjailrs exits with 0 once the jail is done, whatever its command
+    // returned
+    let code = jail.spawn()?.wait()?;
+    log::debug!("Jail exited with {}", code);
+    Ok(())
+}
+
+fn exit_with_retcode(res: Result<(), Errcode>) {
+    match res {
+        Ok(_) => {
+            log::debug!("Exit without any error, returning 0");
+            exit(0);
+        },
+
+        Err(e) => {
+            let retcode = e.get_retcode();
+            log::error!("Error on exit:\n\t{}\n\tReturning {}", e, retcode);
+            exit(retcode);
+        }
+    }
+}
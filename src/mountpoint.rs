@@ -1,43 +1,345 @@
 // Chroot mount point management
 use crate::errors::Errcode;
+use crate::mountspec::{is_overlay_layer, MountSpec};
 use crate::utils::generate_random_str;
 
 use rustix::fd::AsFd;
 use rustix::fs::CWD;
 use rustix::mount::{open_tree, move_mount, OpenTreeFlags, MoveMountFlags};
-use std::path::PathBuf;
-use std::fs::{create_dir_all, remove_dir, remove_dir_all};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use std::fs::{create_dir_all, read_link, remove_dir, remove_dir_all, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::process::Command;
+use which::which;
+use nix::errno::Errno;
 use nix::mount::{mount, MsFlags, umount2, MntFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
 use nix::unistd::{pivot_root, chdir};
 
-// TODO what was the issue with this function?
-#[allow(dead_code)]
-pub fn set_container_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, PathBuf)>) -> Result<(), Errcode> {
+// Host paths bound read only in the minimal root
+const MINIMAL_ROOT_BINDS: [&str; 15] = [
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32",
+    "/etc/ssl", "/etc/alternatives", "/etc/ld.so.cache", "/etc/resolv.conf",
+    "/etc/hosts", "/etc/nsswitch.conf", "/etc/passwd", "/etc/group",
+];
+// Host devices bound in the container, besides the minimal ones
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DevicePolicy {
+    // Devices or directories of devices such as /dev/snd
+    pub allowed: Vec<PathBuf>,
+}
+
+impl DevicePolicy {
+    pub fn validate(&self) -> Result<(), Errcode> {
+        for device in self.allowed.iter() {
+            let below_dev = device.strip_prefix("/dev").map(|rest| {
+                rest.components().count() > 0 && rest.components().all(|c| matches!(c, Component::Normal(_)))
+            });
+            if below_dev != Ok(true) {
+                return Err(Errcode::ProfileError(format!("Device {} is not below /dev", device.display())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Paths of /proc hidden from the container, or that it can only read
+const PROC_MASKED: [&str; 9] = [
+    "acpi", "asound", "kcore", "keys", "latency_stats", "timer_list", "timer_stats", "sched_debug", "scsi",
+];
+const PROC_READONLY: [&str; 5] = ["bus", "fs", "irq", "sys", "sysrq-trigger"];
+const MINIMAL_DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+// Directory for the root of the container, its random suffix is kept in the
+// configuration so that clean_mounts can remove it
+pub fn generate_root_path() -> PathBuf {
+    PathBuf::from(format!("/tmp/orjail.{}", generate_random_str(10)))
+}
+
+// Bind mount_dir on new_root, together with the additional paths, and make it
+// the root of the mount namespace. The old root is detached, so that the
+// container has no access to the host filesystem.
+pub fn set_container_mountpoint(mount_dir: &PathBuf, new_root: &PathBuf, mounts: &[MountSpec], devices: &DevicePolicy) -> Result<(), Errcode> {
     log::debug!("setting mount points");
     // Setting the mount flags
     // MS_PRIVATE prevents any mount/unmount operation to be propagated
     // MS_REC applies it recursively
     // This will remount the root of our filesystem to avoid the propagation
-    // of any new mount operation
-    // TODO bubblewrap performs it on the "old_mount" directory, check how to improve it
+    // of any new mount operation, pivot_root refuses shared mounts as well
     mount_directory(None, &PathBuf::from("/"), vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE])?;
 
     // Create the target directory
-    // TODO move this suffix to a global variable with the lifetime of the program
-    // ie 'static, so that the clean_mounts function can unmount and clean it
-    let random_suffix =generate_random_str(10);
-    let new_root = PathBuf::from(format!("/tmp/orjail.{}", random_suffix));
-    log::debug!("Mounting new root: {}", new_root.as_path().to_str().unwrap());
-    create_directory(&new_root)?;
-    // MS_BIND to create a bind mount that is visible outside the mounted filesystem
-    mount_directory(Some(&mount_dir), &new_root, vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE])?;
-
-    // Mount additional paths present in the configuration
-    for (inpath, mntpath) in addpaths.iter(){
-        let outpath = new_root.join(mntpath);
-        create_directory(&outpath)?;
-        mount_directory(Some(inpath), &outpath, vec![MsFlags::MS_PRIVATE, MsFlags::MS_BIND])?;
+    log::debug!("Mounting new root: {}", new_root.display());
+    create_directory(new_root)?;
+    // MS_BIND to create a bind mount that is visible outside the mounted filesystem,
+    // MS_REC to bring along the mounts below mount_dir
+    mount_directory(Some(mount_dir), new_root, vec![MsFlags::MS_BIND, MsFlags::MS_REC])?;
+
+    // Mounted before the pivot, the kernel wants a fully visible /proc in the
+    // namespace to allow a new one
+    mount_proc(&new_root.join("proc"))?;
+    mount_minimal_dev(&new_root.join("dev"), devices)?;
+    mount_specs(new_root, mounts)?;
+    pivot_into(new_root)
+}
+
+// Without a new root /proc is replaced and only the home overlay is mounted,
+// in place: the other mounts would create their destinations on the host.
+// The overlay comes before /dev is replaced, fuse-overlayfs needs /dev/fuse.
+pub fn set_host_root(mounts: &[MountSpec], devices: &DevicePolicy) -> Result<(), Errcode> {
+    mount_proc(&PathBuf::from("/proc"))?;
+    let home: Vec<MountSpec> = mounts.iter().filter(|spec| matches!(spec, MountSpec::Home { .. })).cloned().collect();
+    mount_specs(Path::new("/"), &home)?;
+    mount_minimal_dev(&PathBuf::from("/dev"), devices)
+}
+
+// Build a minimal root in a tmpfs on new_root, bubblewrap style: the system
+// directories of the host are bound read only, /tmp, /run and /dev are fresh
+// and /proc shows the PID namespace of the container. The host home, /var and
+// everything else are not visible unless added.
+pub fn set_minimal_root(new_root: &PathBuf, mounts: &[MountSpec], devices: &DevicePolicy) -> Result<(), Errcode> {
+    log::debug!("Building minimal root in {}", new_root.display());
+    mount_directory(None, &PathBuf::from("/"), vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE])?;
+    create_directory(new_root)?;
+    mount_filesystem("tmpfs", new_root, vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV], Some("mode=0755"))?;
+
+    for path in MINIMAL_ROOT_BINDS.iter() {
+        bind_host_path(new_root, &PathBuf::from(path))?;
+    }
+
+    for path in ["tmp", "run"] {
+        let target = new_root.join(path);
+        create_directory(&target)?;
+        mount_filesystem("tmpfs", &target, vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV], Some("mode=1777"))?;
+    }
+    mount_minimal_dev(&new_root.join("dev"), devices)?;
+
+    mount_proc(&new_root.join("proc"))?;
+
+    mount_specs(new_root, mounts)?;
+    pivot_into(new_root)
+}
+
+// New procfs showing the PID namespace of the container, with the OCI default
+// masked and read only paths
+pub fn mount_proc(proc_path: &PathBuf) -> Result<(), Errcode> {
+    create_directory(proc_path)?;
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    // hidepid=invisible needs Linux 5.8
+    if let Err(e) = mount(Some("proc"), proc_path, Some("proc"), flags, Some("hidepid=invisible")) {
+        log::warn!("Can not mount /proc with hidepid=invisible ({}), mounting it without", e);
+        mount_filesystem("proc", proc_path, vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV, MsFlags::MS_NOEXEC], None)?;
+    }
+
+    for name in PROC_MASKED.iter() {
+        let path = proc_path.join(name);
+        if path.is_dir() {
+            mount_filesystem("tmpfs", &path, vec![MsFlags::MS_RDONLY], Some("size=0"))?;
+        } else if path.exists() {
+            mount_directory(Some(&PathBuf::from("/dev/null")), &path, vec![MsFlags::MS_BIND])?;
+        }
+    }
+    for name in PROC_READONLY.iter() {
+        let path = proc_path.join(name);
+        if path.exists() {
+            mount_directory(Some(&path), &path, vec![MsFlags::MS_BIND, MsFlags::MS_REC])?;
+            remount_bind(&path, vec![MsFlags::MS_RDONLY])?;
+        }
+    }
+    Ok(())
+}
+
+// Device nodes can not be created in a user namespace, the ones of the host
+// are bound instead. /dev is built aside and moved in place, as the host
+// devices are not reachable anymore once a tmpfs covers /dev.
+pub fn mount_minimal_dev(dev: &PathBuf, devices: &DevicePolicy) -> Result<(), Errcode> {
+    let staging = PathBuf::from(format!("/tmp/orjail-dev.{}", generate_random_str(10)));
+    create_directory(&staging)?;
+    mount_filesystem("tmpfs", &staging, vec![MsFlags::MS_NOSUID, MsFlags::MS_NOEXEC], Some("mode=0755"))?;
+
+    let host_dev = PathBuf::from("/dev");
+    let allowed = devices.allowed.iter().filter_map(|device| device.strip_prefix("/dev").ok());
+    for device in MINIMAL_DEVICES.iter().map(Path::new).chain(allowed) {
+        let (source, target) = (host_dev.join(device), staging.join(device));
+        if source.is_dir() {
+            create_directory(&target)?;
+        } else if source.exists() {
+            if let Some(parent) = target.parent() {
+                create_directory(&parent.to_path_buf())?;
+            }
+            create_file(&target)?;
+        } else {
+            log::warn!("Device {} is missing on the host, skipping it", source.display());
+            continue;
+        }
+        mount_directory(Some(&source), &target, vec![MsFlags::MS_BIND, MsFlags::MS_REC])?;
     }
+
+    let pts = staging.join("pts");
+    create_directory(&pts)?;
+    mount_filesystem("devpts", &pts, vec![MsFlags::MS_NOSUID, MsFlags::MS_NOEXEC], Some("newinstance,ptmxmode=0666,mode=620"))?;
+    create_directory(&staging.join("shm"))?;
+    mount_filesystem("tmpfs", &staging.join("shm"), vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV], Some("mode=1777"))?;
+
+    for (link, target) in [("ptmx", "pts/ptmx"), ("fd", "/proc/self/fd"), ("stdin", "/proc/self/fd/0"),
+                           ("stdout", "/proc/self/fd/1"), ("stderr", "/proc/self/fd/2")] {
+        create_symlink(&PathBuf::from(target), &staging.join(link))?;
+    }
+
+    create_directory(dev)?;
+    mount_directory(Some(&staging), dev, vec![MsFlags::MS_MOVE])?;
+    delete_dir(&staging)
+}
+
+// Read only bind of a host path in new_root. Symbolic links, e.g. /bin on
+// merged /usr systems, are copied and paths missing on the host are skipped.
+fn bind_host_path(new_root: &Path, path: &PathBuf) -> Result<(), Errcode> {
+    let target = new_root.join(path.strip_prefix("/").unwrap_or(path));
+    if let Ok(link) = read_link(path) {
+        if let Some(parent) = target.parent() {
+            create_directory(&parent.to_path_buf())?;
+        }
+        return create_symlink(&link, &target);
+    }
+    if path.is_dir() {
+        create_directory(&target)?;
+    } else if path.exists() {
+        create_file(&target)?;
+    } else {
+        log::debug!("Skipping {}, missing on the host", path.display());
+        return Ok(());
+    }
+    mount_directory(Some(path), &target, vec![MsFlags::MS_BIND, MsFlags::MS_REC])?;
+    remount_bind(&target, vec![MsFlags::MS_RDONLY])
+}
+
+// Mount additional paths present in the configuration
+fn mount_specs(new_root: &Path, mounts: &[MountSpec]) -> Result<(), Errcode> {
+    for spec in mounts.iter() {
+        let target = match spec.destination().strip_prefix("/") {
+            Ok(destination) => new_root.join(destination),
+            Err(_) => return Err(Errcode::MountsError(format!("Destination {} is not absolute", spec.destination().display()))),
+        };
+        log::debug!("Mounting {:?}", spec);
+        match spec {
+            MountSpec::Bind { source, options, .. } => {
+                if source.is_dir() {
+                    create_directory(&target)?;
+                } else {
+                    create_file(&target)?;
+                }
+                mount_directory(Some(source), &target, vec![MsFlags::MS_BIND, MsFlags::MS_REC])?;
+
+                let mut flags = vec![];
+                for (set, flag) in [(options.readonly, MsFlags::MS_RDONLY), (options.noexec, MsFlags::MS_NOEXEC),
+                                    (options.nosuid, MsFlags::MS_NOSUID), (options.nodev, MsFlags::MS_NODEV)] {
+                    if set {
+                        flags.push(flag);
+                    }
+                }
+                if !flags.is_empty() {
+                    remount_bind(&target, flags)?;
+                }
+            }
+            MountSpec::Tmpfs { size, .. } => {
+                create_directory(&target)?;
+                let data = match size {
+                    Some(size) => format!("mode=1777,size={}", size),
+                    None => "mode=1777".to_string(),
+                };
+                mount_filesystem("tmpfs", &target, vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV], Some(&data))?;
+            }
+            MountSpec::Overlay { lower, .. } => {
+                create_directory(&target)?;
+                mount_overlay(Some(lower), None, &target)?;
+            }
+            MountSpec::Home { home, persist } => {
+                create_directory(&target)?;
+                // Without a home on the host the jail starts from an empty
+                // one. A persisted home keeps its layers below the home,
+                // which overlayfs refuses as lower layer: it starts empty too.
+                let lower = if home.is_dir() && persist.is_none() { Some(home) } else { None };
+                mount_overlay(lower, persist.as_ref(), &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Overlay on target, the upper layer lives in persist or else in a tmpfs that
+// is detached once the overlay holds it. Kernels that do not allow overlayfs in
+// user namespaces, before 5.11, fall back to fuse-overlayfs.
+fn mount_overlay(lower: Option<&PathBuf>, persist: Option<&PathBuf>, target: &PathBuf) -> Result<(), Errcode> {
+    let staging = PathBuf::from(format!("/tmp/orjail-overlay.{}", generate_random_str(10)));
+    create_directory(&staging)?;
+    mount_filesystem("tmpfs", &staging, vec![MsFlags::MS_NOSUID, MsFlags::MS_NODEV], Some("mode=0755"))?;
+
+    let (lower, upper, work) = overlay_layers(lower, persist, &staging)?;
+    for dir in [&lower, &upper, &work] {
+        create_directory(dir)?;
+    }
+
+    let data = overlay_data(&lower, &upper, &work)?;
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    if let Err(e) = mount(Some("overlay"), target, Some("overlay"), flags, Some(data.as_str())) {
+        log::warn!("Kernel overlayfs is not available in the user namespace ({}), trying fuse-overlayfs", e);
+        mount_fuse_overlay(&data, target)?;
+    }
+
+    unmount_path(&staging)?;
+    delete_dir(&staging)
+}
+
+// Lower, upper and work directories, the missing ones in staging. Since Linux
+// 5.2 overlayfs refuses layers inside one another.
+fn overlay_layers(lower: Option<&PathBuf>, persist: Option<&PathBuf>, staging: &Path) -> Result<(PathBuf, PathBuf, PathBuf), Errcode> {
+    let lower = match lower {
+        Some(lower) => lower.clone(),
+        None => staging.join("lower"),
+    };
+    let (upper, work) = match persist {
+        Some(persist) => (persist.join("upper"), persist.join("work")),
+        None => (staging.join("upper"), staging.join("work")),
+    };
+    for layer in [&upper, &work] {
+        if layer.starts_with(&lower) || lower.starts_with(layer) {
+            log::error!("Overlay layer {} overlaps the lower directory {}", layer.display(), lower.display());
+            return Err(Errcode::MountsError(format!("Overlay layers {} and {} overlap", layer.display(), lower.display())));
+        }
+    }
+    Ok((lower, upper, work))
+}
+
+fn overlay_data(lower: &Path, upper: &Path, work: &Path) -> Result<String, Errcode> {
+    for layer in [lower, upper, work] {
+        if !is_overlay_layer(layer) {
+            log::error!("Can not use {} as an overlay layer, it is not UTF-8 or holds ',' or ':'", layer.display());
+            return Err(Errcode::MountsError(format!("Invalid overlay layer {}", layer.display())));
+        }
+    }
+    Ok(format!("lowerdir={},upperdir={},workdir={}", lower.display(), upper.display(), work.display()))
+}
+
+fn mount_fuse_overlay(data: &str, target: &PathBuf) -> Result<(), Errcode> {
+    let fuse_overlayfs = match which("fuse-overlayfs") {
+        Ok(path) => path,
+        Err(e) => {
+            log::error!("Can not mount overlay on {}, fuse-overlayfs is not available: {}", target.display(), e);
+            return Err(Errcode::MountsError(format!("No overlayfs support for {}: {}", target.display(), e)));
+        }
+    };
+    // fuse-overlayfs goes to the background once the mount is ready
+    match Command::new(fuse_overlayfs).arg("-o").arg(data).arg(target).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Errcode::MountsError(format!("fuse-overlayfs on {} exited with {}", target.display(), status))),
+        Err(e) => Err(Errcode::IoError(format!("Can not run fuse-overlayfs on {}", target.display()), e)),
+    }
+}
+
+fn pivot_into(new_root: &PathBuf) -> Result<(), Errcode> {
     // MAGIC: now we set the /tmp/orjail. directory as the new / root filesystem, and we will
     // move the old / root filesystem in a new directory in /tmp/orjail./oldroot.
     // We will then take the hurdle of unmounting it to avoid that the container
@@ -45,10 +347,10 @@ pub fn set_container_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, Pa
     let old_root_name = format!("oldroot.{}", generate_random_str(6));
     let old_root = new_root.join(PathBuf::from(old_root_name.clone()));
     create_directory(&old_root)?;
-    log::debug!("Pivoting root to {}", old_root.as_path().to_str().unwrap());
-    if let Err(e) = pivot_root(&new_root, &old_root) {
+    log::debug!("Pivoting root to {}", old_root.display());
+    if let Err(e) = pivot_root(new_root, &old_root) {
         return Err(Errcode::MountsError(format!("Can not change root mount point from {} to {} : {}",
-                    old_root.to_str().unwrap(), new_root.to_str().unwrap(), e)));
+                    old_root.display(), new_root.display(), e)));
     }
 
     // Now we unmount the old root, and we also take care of being out of
@@ -56,7 +358,7 @@ pub fn set_container_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, Pa
     let root_inside_container = PathBuf::from(format!("/{}", old_root_name));
     if let Err(e) = chdir(&PathBuf::from("/")) {
         log::error!("Cannot change cwd to root: {}", e);
-        return Err(Errcode::MountsError(format!("Cannot change cwd to root: {}", e)));
+        return Err(Errcode::SysError("Cannot change cwd to root".to_string(), e));
     }
     unmount_path(&root_inside_container)?;
     delete_dir(&root_inside_container)?;
@@ -64,6 +366,26 @@ pub fn set_container_mountpoint(mount_dir: &PathBuf, addpaths: &Vec<(PathBuf, Pa
     Ok(())
 }
 
+// Flags of a bind mount, such as MS_RDONLY, are only applied by a remount. In
+// a user namespace the flags that the host locked on the source must be kept.
+pub fn remount_bind(path: &PathBuf, extra: Vec<MsFlags>) -> Result<(), Errcode> {
+    let locked = match statvfs(path) {
+        Ok(stat) => stat.flags(),
+        Err(e) => return Err(Errcode::SysError(format!("Cannot stat {}", path.display()), e)),
+    };
+    let mut flags = vec![MsFlags::MS_REMOUNT, MsFlags::MS_BIND];
+    flags.extend(extra);
+    for (st_flag, ms_flag) in [(FsFlags::ST_RDONLY, MsFlags::MS_RDONLY), (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+                               (FsFlags::ST_NODEV, MsFlags::MS_NODEV), (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+                               (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME), (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+                               (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME)] {
+        if locked.contains(st_flag) {
+            flags.push(ms_flag);
+        }
+    }
+    mount_directory(None, path, flags)
+}
+
 pub fn remount_root() -> Result<(), Errcode> {
     if let Err(e) = mount_directory(Some(&PathBuf::from("none")), &PathBuf::from("/"), vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE]) {
         return Err(e);
@@ -84,21 +406,55 @@ pub fn mount_directory(path: Option<&PathBuf>, mount_point: &PathBuf, flags: Vec
         Ok(_) => Ok(()),
         Err(e) => {
             if let Some(p) = path {
-                Err(Errcode::MountsError(format!("Cannot mount {} to {}: {}", p.to_str().unwrap(), mount_point.to_str().unwrap(), e)))
+                Err(Errcode::SysError(format!("Cannot mount {} to {}", p.display(), mount_point.display()), e))
             } else {
-                Err(Errcode::MountsError(format!("Cannot remount {}: {}", mount_point.to_str().unwrap(), e)))
+                Err(Errcode::SysError(format!("Cannot remount {}", mount_point.display()), e))
             }
         }
     }
 }
 
+pub fn mount_filesystem(fstype: &str, mount_point: &PathBuf, flags: Vec<MsFlags>, data: Option<&str>) -> Result<(), Errcode> {
+    let mut ms_flags = MsFlags::empty();
+    for f in flags.iter() {
+        ms_flags.insert(*f);
+    }
+
+    match mount::<str, PathBuf, str, str>(Some(fstype), mount_point, Some(fstype), ms_flags, data) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("Cannot mount {} on {}: {}", fstype, mount_point.display(), e);
+            Err(Errcode::SysError(format!("Cannot mount {} on {}", fstype, mount_point.display()), e))
+        }
+    }
+}
+
 
 pub fn create_directory(path: &PathBuf) -> Result<(), Errcode> {
     match create_dir_all(path) {
         Ok(_) => Ok(()),
         Err(e) => {
-            log::error!("Cannot create directory {} : {}", path.to_str().unwrap(), e);
-            Err(Errcode::MountsError(format!("Cannot create directory {} : {}", path.to_str().unwrap(), e)))
+            log::error!("Cannot create directory {} : {}", path.display(), e);
+            Err(Errcode::IoError(format!("Cannot create directory {}", path.display()), e))
+        }
+    }
+}
+
+// Mount point for the bind of a file
+pub fn create_file(path: &PathBuf) -> Result<(), Errcode> {
+    if let Err(e) = OpenOptions::new().create(true).truncate(false).write(true).open(path) {
+        log::error!("Cannot create file {} : {}", path.display(), e);
+        return Err(Errcode::IoError(format!("Cannot create file {}", path.display()), e));
+    }
+    Ok(())
+}
+
+pub fn create_symlink(target: &PathBuf, link: &PathBuf) -> Result<(), Errcode> {
+    match symlink(target, link) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("Cannot create symlink {} : {}", link.display(), e);
+            Err(Errcode::IoError(format!("Cannot create symlink {}", link.display()), e))
         }
     }
 }
@@ -107,49 +463,110 @@ pub fn unmount_path(path: &PathBuf) -> Result<(), Errcode> {
     match umount2(path, MntFlags::MNT_DETACH) {
         Ok(_) => Ok(()),
         Err(e) => {
-            log::error!("Unable to detach directory {}: {}", path.to_str().unwrap(), e);
-            Err(Errcode::MountsError(format!("Unable to detach directory {}: {}", path.to_str().unwrap(), e)))
+            log::error!("Unable to detach directory {}: {}", path.display(), e);
+            Err(Errcode::SysError(format!("Unable to detach directory {}", path.display()), e))
         }
     }
 }
 
-pub fn delete_dir(path: &PathBuf) -> Result<(), Errcode> {
-    match remove_dir(path.as_path()) {
+pub fn delete_dir(path: &Path) -> Result<(), Errcode> {
+    match remove_dir(path) {
         Ok(_) => Ok(()),
         Err(e) => {
-            log::error!("Unable to delete directory {} : {}", path.to_str().unwrap(), e);
-            Err(Errcode::MountsError(format!("Unable to delete directory {} : {}", path.to_str().unwrap(), e)))
+            log::error!("Unable to delete directory {} : {}", path.display(), e);
+            Err(Errcode::IoError(format!("Unable to delete directory {}", path.display()), e))
         }
     }
 }
 
-pub fn clean_mounts(mount_root: &Option<PathBuf>, hostname: &String) -> Result<(), Errcode> {
+pub fn clean_mounts(root: &Option<PathBuf>, hostname: &String) -> Result<(), Errcode> {
     let tmp_path = PathBuf::from(format!("/tmp/{}", hostname));
-    if let Err(e) = remove_dir_all(&tmp_path) {
-        log::error!("Can not clean {}: {}", tmp_path.to_str().unwrap(), e);
-        return Err(Errcode::MountsError(format!("Unable to delete tmp dir: {}", e)));
-    };
-    // TODO complete this function, in order to do it we need to keep track
-    // of the random suffix of the root mountpoint
-    match mount_root {
-        Some(rootpath) => unmount_path(rootpath),
-        None => Ok(()),
+    match remove_dir_all(&tmp_path) {
+        Ok(_) => {},
+        // Already gone if the setup of the jail was rolled back
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => {
+            log::error!("Can not clean {}: {}", tmp_path.display(), e);
+            return Err(Errcode::IoError("Unable to delete tmp dir".to_string(), e));
+        }
+    }
+
+    if let Some(root) = root {
+        // The root is mounted in the namespace of the container, and is
+        // usually gone with it
+        match umount2(root, MntFlags::MNT_DETACH) {
+            Ok(_) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {},
+            Err(e) => {
+                log::error!("Unable to detach directory {}: {}", root.display(), e);
+                return Err(Errcode::SysError(format!("Unable to detach directory {}", root.display()), e));
+            }
+        }
+        // Never remove recursively, the directory could still hold the
+        // mount of the user directory
+        if root.exists() {
+            delete_dir(root)?;
+        }
     }
+    Ok(())
 }
 
 pub fn bind_mount_namespace(from_path: &PathBuf, to_path: &PathBuf) -> Result<(), Errcode>  {
     // This function mimicks the behaviour of mount --bind dir1 dir2
-    let from_fd = open_tree(CWD, from_path, OpenTreeFlags::OPEN_TREE_CLONE | OpenTreeFlags::OPEN_TREE_CLOEXEC);
-    if from_fd.is_err() {
-        log::error!("Cannot open target path {}: {:?}", from_path.to_str().unwrap(), from_fd);
-        return Err(Errcode::MountsError(format!("Cannot open target path {}: {:?}", from_path.to_str().unwrap(), from_fd)));
-    }
+    let from_fd = match open_tree(CWD, from_path, OpenTreeFlags::OPEN_TREE_CLONE | OpenTreeFlags::OPEN_TREE_CLOEXEC) {
+        Ok(fd) => fd,
+        Err(e) => {
+            log::error!("Cannot open target path {}: {:?}", from_path.display(), e);
+            return Err(Errcode::SysError(format!("Cannot open target path {}", from_path.display()), Errno::from_i32(e.raw_os_error())));
+        }
+    };
 
-    if let Err(e) = move_mount(from_fd.unwrap().as_fd(), "", CWD, to_path, MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH) {
-        log::error!("Can not mount {} to {}: {:?}", from_path.to_str().unwrap(), to_path.to_str().unwrap(), e);
-        return Err(Errcode::MountsError(format!("Can not mount {} to {}: {:?}", from_path.to_str().unwrap(), to_path.to_str().unwrap(), e)));
+    if let Err(e) = move_mount(from_fd.as_fd(), "", CWD, to_path, MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH) {
+        log::error!("Can not mount {} to {}: {:?}", from_path.display(), to_path.display(), e);
+        return Err(Errcode::SysError(format!("Can not mount {} to {}", from_path.display(), to_path.display()), Errno::from_i32(e.raw_os_error())));
     }
 
     Ok(())
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn persisted_home_has_an_empty_lower() {
+        let (home, persist) = (PathBuf::from("/home/user"), PathBuf::from("/home/user/.local/share/orjail/homes/work"));
+        let staging = Path::new("/tmp/orjail-overlay.test");
+        let (lower, upper, work) = overlay_layers(None, Some(&persist), staging).unwrap();
+        assert_eq!(lower, staging.join("lower"));
+        assert_eq!((upper, work), (persist.join("upper"), persist.join("work")));
+
+        // Kept below the home, the layers can not sit on it
+        assert!(matches!(overlay_layers(Some(&home), Some(&persist), staging), Err(Errcode::MountsError(_))));
+    }
+
+    #[test]
+    fn ephemeral_layers_live_in_staging() {
+        let home = PathBuf::from("/home/user");
+        let staging = Path::new("/tmp/orjail-overlay.test");
+        let (lower, upper, work) = overlay_layers(Some(&home), None, staging).unwrap();
+        assert_eq!((lower, upper, work), (home, staging.join("upper"), staging.join("work")));
+    }
+
+    #[test]
+    fn overlay_data_of_the_layers() {
+        let data = overlay_data(Path::new("/home/user"), Path::new("/tmp/o/upper"), Path::new("/tmp/o/work")).unwrap();
+        assert_eq!(data, "lowerdir=/home/user,upperdir=/tmp/o/upper,workdir=/tmp/o/work");
+    }
+
+    #[test]
+    fn overlay_data_refuses_paths_it_can_not_hold() {
+        let (upper, work) = (Path::new("/tmp/o/upper"), Path::new("/tmp/o/work"));
+        let not_utf8 = Path::new(OsStr::from_bytes(b"/home/\xff"));
+        for lower in [Path::new("/home/a,upperdir=/etc"), Path::new("/home/a:/etc"), not_utf8] {
+            assert!(matches!(overlay_data(lower, upper, work), Err(Errcode::MountsError(_))), "{}", lower.display());
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// Additional mounts of the container, parsed from the command line
+use crate::errors::Errcode;
+
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MountSpec {
+    // --add src:dst[:options]
+    Bind { source: PathBuf, destination: PathBuf, options: MountOptions },
+    // --tmpfs dst[:size]
+    Tmpfs { destination: PathBuf, size: Option<String> },
+    // --overlay lower:dst, writes go to a tmpfs thrown away on exit
+    Overlay { lower: PathBuf, destination: PathBuf },
+    // --ephemeral-home and --persist-home, an overlay on the home of the user
+    // whose writes are thrown away or kept in persist
+    Home { home: PathBuf, persist: Option<PathBuf> },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct MountOptions {
+    pub readonly: bool,
+    pub noexec: bool,
+    pub nosuid: bool,
+    pub nodev: bool,
+}
+
+impl MountSpec {
+    pub fn parse_bind(spec: &str) -> Result<MountSpec, Errcode> {
+        let mut parts = spec.splitn(3, ':');
+        let (source, destination) = match (parts.next(), parts.next()) {
+            (Some(source), Some(destination)) if !source.is_empty() => (source, destination),
+            _ => {
+                log::error!("Invalid mount {}, expected src:dst[:options]", spec);
+                return Err(Errcode::ArgumentInvalid("add".to_string()));
+            }
+        };
+
+        let mut options = MountOptions::default();
+        for option in parts.next().unwrap_or("").split(',').filter(|o| !o.is_empty()) {
+            match option {
+                "ro" => options.readonly = true,
+                "rw" => options.readonly = false,
+                "noexec" => options.noexec = true,
+                "nosuid" => options.nosuid = true,
+                "nodev" => options.nodev = true,
+                _ => {
+                    log::error!("Unknown option {} of mount {}", option, spec);
+                    return Err(Errcode::ArgumentInvalid("add".to_string()));
+                }
+            }
+        }
+
+        Ok(MountSpec::Bind {
+            source: host_path(source, "add")?,
+            destination: destination_path(destination, "add")?,
+            options,
+        })
+    }
+
+    pub fn parse_tmpfs(spec: &str) -> Result<MountSpec, Errcode> {
+        let (destination, size) = match spec.split_once(':') {
+            Some((destination, size)) => (destination, Some(size)),
+            None => (spec, None),
+        };
+
+        // Sizes as tmpfs takes them: bytes with an optional k, m, g or % suffix
+        if let Some(size) = size {
+            let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) || size.len() - digits.len() > 1 {
+                log::error!("Invalid size {} of tmpfs {}", size, destination);
+                return Err(Errcode::ArgumentInvalid("tmpfs".to_string()));
+            }
+        }
+
+        Ok(MountSpec::Tmpfs {
+            destination: destination_path(destination, "tmpfs")?,
+            size: size.map(String::from),
+        })
+    }
+
+    pub fn parse_overlay(spec: &str) -> Result<MountSpec, Errcode> {
+        let (lower, destination) = match spec.split_once(':') {
+            Some((lower, destination)) if !lower.is_empty() => (host_path(lower, "overlay")?, destination),
+            _ => {
+                log::error!("Invalid overlay {}, expected lower:dst", spec);
+                return Err(Errcode::ArgumentInvalid("overlay".to_string()));
+            }
+        };
+        // Checked once resolved, a symbolic link could lead to such a path
+        if !is_overlay_layer(&lower) {
+            log::error!("Invalid lower directory {}, overlay layers can not hold ',' or ':'", lower.display());
+            return Err(Errcode::ArgumentInvalid("overlay".to_string()));
+        }
+        Ok(MountSpec::Overlay {
+            lower,
+            destination: destination_path(destination, "overlay")?,
+        })
+    }
+
+    // The persistent upper layer of a home is kept in
+    // ~/.local/share/orjail/homes/<name>, created when the home is mounted
+    pub fn home(home: PathBuf, persist: Option<&str>) -> Result<MountSpec, Errcode> {
+        let persist = match persist {
+            Some(name) => {
+                if name.is_empty() || name.starts_with('.') || name.contains('/') {
+                    log::error!("Invalid home name {}", name);
+                    return Err(Errcode::ArgumentInvalid("persist-home".to_string()));
+                }
+                Some(home.join(".local/share/orjail/homes").join(name))
+            }
+            None => None,
+        };
+        Ok(MountSpec::Home { home, persist })
+    }
+
+    // The variants can be built without the parse functions, checked again
+    // before the jail is created
+    pub fn validate(&self) -> Result<(), Errcode> {
+        if !is_destination(self.destination()) {
+            log::error!("Invalid destination {}, expected an absolute path below /", self.destination().display());
+            return Err(Errcode::ArgumentInvalid("mount".to_string()));
+        }
+        if let MountSpec::Overlay { lower, .. } = self {
+            if !is_overlay_layer(lower) {
+                log::error!("Invalid lower directory {}, overlay layers can not hold ',' or ':'", lower.display());
+                return Err(Errcode::ArgumentInvalid("mount".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn destination(&self) -> &PathBuf {
+        match self {
+            MountSpec::Bind { destination, .. } => destination,
+            MountSpec::Tmpfs { destination, .. } => destination,
+            MountSpec::Overlay { destination, .. } => destination,
+            MountSpec::Home { home, .. } => home,
+        }
+    }
+}
+
+// Sources are resolved on the host, before the root of the container changes
+fn host_path(path: &str, arg: &'static str) -> Result<PathBuf, Errcode> {
+    match PathBuf::from(path).canonicalize() {
+        Ok(path) => Ok(path),
+        Err(e) => {
+            log::error!("Can not resolve {}: {}", path, e);
+            Err(Errcode::ArgumentInvalid(arg.to_string()))
+        }
+    }
+}
+
+// Destinations are absolute paths in the container that can not get out of it
+fn destination_path(path: &str, arg: &'static str) -> Result<PathBuf, Errcode> {
+    let path = Path::new(path);
+    if !is_destination(path) {
+        log::error!("Invalid destination {}, expected an absolute path below /", path.display());
+        return Err(Errcode::ArgumentInvalid(arg.to_string()));
+    }
+    Ok(path.to_path_buf())
+}
+
+fn is_destination(path: &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+        && path.components().count() > 1
+}
+
+// Layers are given to overlayfs in its options, where ',' separates the
+// options and ':' the lower layers
+pub fn is_overlay_layer(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| !path.contains([',', ':']))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_with_options() {
+        match MountSpec::parse_bind("/tmp:/data:ro,nosuid").unwrap() {
+            MountSpec::Bind { destination, options, .. } => {
+                assert_eq!(destination, PathBuf::from("/data"));
+                assert!(options.readonly && options.nosuid && !options.noexec && !options.nodev);
+            }
+            spec => panic!("unexpected {:?}", spec),
+        }
+        assert!(MountSpec::parse_bind("/tmp:/data:ro,rw").is_ok());
+        assert!(MountSpec::parse_bind("/tmp:/data:exec").is_err());
+        assert!(MountSpec::parse_bind(":/data").is_err());
+        assert!(MountSpec::parse_bind("/tmp").is_err());
+    }
+
+    #[test]
+    fn destinations_stay_below_the_root() {
+        for destination in ["/data/../../etc", "/..", "../data", "data", "./data", "/", ""] {
+            assert!(MountSpec::parse_bind(&format!("/tmp:{destination}")).is_err(), "{destination}");
+            assert!(MountSpec::parse_tmpfs(destination).is_err(), "{destination}");
+            assert!(MountSpec::parse_overlay(&format!("/tmp:{destination}")).is_err(), "{destination}");
+        }
+        assert!(MountSpec::parse_tmpfs("/var/cache").is_ok());
+    }
+
+    #[test]
+    fn tmpfs_sizes() {
+        for size in ["1024", "64k", "64M", "1g", "50%"] {
+            assert!(MountSpec::parse_tmpfs(&format!("/scratch:{size}")).is_ok(), "{size}");
+        }
+        for size in ["", "m", "64mb", "1.5g", "-1", "64kk"] {
+            assert!(MountSpec::parse_tmpfs(&format!("/scratch:{size}")).is_err(), "{size}");
+        }
+    }
+
+    #[test]
+    fn overlay_needs_a_lower_directory() {
+        assert!(MountSpec::parse_overlay("/tmp:/work").is_ok());
+        assert!(MountSpec::parse_overlay(":/work").is_err());
+        assert!(MountSpec::parse_overlay("/work").is_err());
+        assert!(MountSpec::parse_overlay("/does/not/exist:/work").is_err());
+    }
+
+    // A ',' in the lower directory would add options to the mount of overlayfs
+    #[test]
+    fn overlay_layers_can_not_add_options() {
+        let lower = std::env::temp_dir().join(format!("orjail-test-{},upperdir=x", std::process::id()));
+        std::fs::create_dir_all(&lower).unwrap();
+        let spec = format!("{}:/work", lower.display());
+        let parsed = MountSpec::parse_overlay(&spec);
+        let _ = std::fs::remove_dir(&lower);
+        assert!(parsed.is_err());
+
+        assert!(MountSpec::Overlay { lower: lower.clone(), destination: PathBuf::from("/work") }.validate().is_err());
+        assert!(!is_overlay_layer(Path::new("/a:/b")));
+        assert!(is_overlay_layer(Path::new("/home/user")));
+    }
+
+    #[test]
+    fn validate_specs_built_directly() {
+        let spec = |destination: &str| MountSpec::Tmpfs { destination: PathBuf::from(destination), size: None };
+        assert!(spec("/scratch").validate().is_ok());
+        for destination in ["scratch", "/scratch/../../etc", "/"] {
+            assert!(spec(destination).validate().is_err(), "{destination}");
+        }
+    }
+
+    #[test]
+    fn home_names() {
+        for name in ["", ".", "..", ".hidden", "a/b"] {
+            assert!(MountSpec::home(PathBuf::from("/nonexistent"), Some(name)).is_err(), "{name}");
+        }
+        assert!(MountSpec::home(PathBuf::from("/home/user"), None).is_ok());
+    }
+
+    // Parsing leaves the host alone, the layers are created by the mount
+    #[test]
+    fn persisted_home_is_not_created_by_parsing() {
+        let home = std::env::temp_dir().join(format!("orjail-test-home-{}", std::process::id()));
+        match MountSpec::home(home.clone(), Some("work")).unwrap() {
+            MountSpec::Home { persist, .. } => assert_eq!(persist, Some(home.join(".local/share/orjail/homes/work"))),
+            spec => panic!("unexpected {:?}", spec),
+        }
+        assert!(!home.exists());
+    }
+}
@@ -4,7 +4,7 @@ use crate::mountpoint::{bind_mount_namespace, create_directory, mount_directory}
 
 use nix::errno::Errno;
 use nix::fcntl::{open, OFlag};
-use nix::mount::{mount, MsFlags};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sched::{CloneFlags, unshare, setns};
 use nix::unistd::{fork, ForkResult, Pid};
 use nix::sys::wait::{waitpid, WaitStatus};
@@ -12,43 +12,44 @@ use nix::sys::stat::{stat, Mode};
 use nix::sys::statvfs::{statvfs, FsFlags};
 use rtnetlink::{new_connection, NetworkNamespace};
 use futures::TryStreamExt;
-use std::fs::{File, OpenOptions};
+use std::fs::{remove_file, File, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::io::Write;
 use std::os::unix::io::{FromRawFd ,RawFd};
 
-// This function will be called by the child during its configuration
-// to create its namespace.
 const UID_COUNT: u64 = 1;
 const GID_COUNT: u64 = 1;
 static RUN: &str = "/run/";
 static NETNS: &str = "/run/netns/";
 static VAR_LIB: &str = "/var/lib";
 
-pub fn userns(real_uid: u32, real_gid: u32, target_uid: u32) -> Result<(), Errcode> {
-    log::debug!("Switching to uid {} / gid {}...", target_uid, target_uid);
+// Called by the parent once the child reports its namespaces as created: the
+// maps of a user namespace are written from its parent namespace, where the
+// real uid has every capability over it
+pub fn userns(pid: Pid, real_uid: u32, real_gid: u32, target_uid: u32) -> Result<(), Errcode> {
+    log::debug!("Mapping uid {} / gid {} of the child to {}...", real_uid, real_gid, target_uid);
 
-    if let Ok(mut uid_map) = File::create("/proc/self/uid_map") {
+    if let Ok(mut uid_map) = OpenOptions::new().write(true).open(format!("/proc/{}/uid_map", pid)) {
         if let Err(e) = uid_map.write_all(format!("{} {} {}", target_uid, real_uid, UID_COUNT).as_bytes()) {
             log::error!("Unable to open UID map: {:?}", e);
-            return Err(Errcode::NamespacesError(format!("Unable to open UID Map: {}", e)));
+            return Err(Errcode::IoError("Unable to open UID Map".to_string(), e));
         }
     } else {
         log::error!("Unable to create the UID MAP");
         return Err(Errcode::NamespacesError("Unable to create UID Map".to_string()));
     }
 
-    if let Ok(mut setgroups) = OpenOptions::new().write(true).open("/proc/self/setgroups") {
+    if let Ok(mut setgroups) = OpenOptions::new().write(true).open(format!("/proc/{}/setgroups", pid)) {
         if let Err(e) = setgroups.write_all("deny".as_bytes()) {
             log::error!("Unable to write to setgroups: {:?}", e);
             return Err(Errcode::NamespacesError(format!("Unable to block setgroups: {}",e ))); }
     }
 
-    if let Ok(mut gid_map) = File::create("/proc/self/gid_map") {
+    if let Ok(mut gid_map) = OpenOptions::new().write(true).open(format!("/proc/{}/gid_map", pid)) {
         if let Err(e) = gid_map.write_all(format!("{} {} {}", target_uid, real_gid, GID_COUNT).as_bytes()) {
             log::error!("Unable to open GID map: {:?}", e);
-            return Err(Errcode::NamespacesError(format!("Unable to open GID Map: {}", e)));
+            return Err(Errcode::IoError("Unable to open GID Map".to_string(), e));
         }
     } else {
         log::error!("Unable to create the GID MAP");
@@ -72,11 +73,24 @@ pub async fn open_namespace(ns_name: &String) -> Result<RawFd, Errcode> {
         Ok(fd) => return Ok(fd),
         Err(e) => {
             log::error!("Can not create network namespace {}: {}", ns_name, e);
-            return Err(Errcode::NamespacesError(format!("Can not create network namespace {}: {}", ns_name, e)));
+            return Err(Errcode::SysError(format!("Can not create network namespace {}", ns_name), e));
         }
     }
 }
 
+// Undo of open_namespace, the namespace is bind mounted on its file
+pub fn remove_namespace(ns_name: &str) -> Result<(), Errcode> {
+    let ns_path = PathBuf::from(format!("{}{}", NETNS, ns_name));
+    match umount2(&ns_path, MntFlags::MNT_DETACH) {
+        Ok(_) | Err(Errno::EINVAL) => {},
+        Err(e) => return Err(Errcode::SysError(format!("Can not unmount network namespace {}", ns_name), e)),
+    }
+    if let Err(e) = remove_file(&ns_path) {
+        return Err(Errcode::IoError(format!("Can not remove network namespace {}", ns_name), e));
+    }
+    Ok(())
+}
+
 pub fn mount_netns(hostname: &String) -> Result<(), Errcode> {
     let mount_dir_name = format!("/tmp/{}", hostname);
     let netns_dir_name = format!("{}/netns", mount_dir_name);
@@ -111,7 +125,7 @@ pub fn mount_netns(hostname: &String) -> Result<(), Errcode> {
         // What else can go wrong?
         Err(e) => {
             log::error!("Unknown error during stat of {}: {}", NETNS, e);
-            return Err(Errcode::NamespacesError(format!("Error stat of {}: {}", NETNS, e)));
+            return Err(Errcode::SysError(format!("Error stat of {}", NETNS), e));
         }
     }
 
@@ -146,7 +160,7 @@ pub async fn run_in_namespace(ns_name: &String, veth_ip: &str, veth_2_ip: &str)
         }
         Err(e) => {
             log::error!("Can not fork() for ns creation: {}", e);
-            return Err(Errcode::NamespacesError(format!("Error fork(): {}",e)));
+            return Err(Errcode::SysError("Error fork()".to_string(), e));
         }
     }
 
@@ -176,7 +190,7 @@ fn run_parent(child: Pid) -> Result<(), Errcode> {
         }
         Err(e) => {
             log::error!("wait error : {}", e);
-            return Err(Errcode::NamespacesError(format!("Error during wait: {}", e)));
+            return Err(Errcode::SysError("Error during wait".to_string(), e));
         }
     }
 
@@ -221,18 +235,18 @@ pub fn split_namespace(ns_name: &String) -> Result<(), Errcode> {
         }
         Err(e) => {
             log::error!("Can not open network namespace: {}", e);
-            return Err(Errcode::NamespacesError(format!("Can not open network namespace: {}", e)));
+            return Err(Errcode::SysError("Can not open network namespace".to_string(), e));
         }
     };
     // Switch to network namespace with CLONE_NEWNET
     if let Err(e) = setns(fd, CloneFlags::CLONE_NEWNET) {
         log::error!("Can not set namespace to target {}: {}", ns_name, e);
-        return Err(Errcode::NamespacesError(format!("Unable to set target namespace: {}", e)));
+        return Err(Errcode::SysError("Unable to set target namespace".to_string(), e));
     }
     // unshare with CLONE_NEWNS
     if let Err(e) = unshare(CloneFlags::CLONE_NEWNS) {
         log::error!("Can not unshare: {}", e);
-        return Err(Errcode::NamespacesError(format!("Can not unshare: {}", e)));
+        return Err(Errcode::SysError("Can not unshare".to_string(), e));
     }
     // mount blind the fs
     // let's avoid that any mount propagates to the parent process
@@ -247,10 +261,13 @@ pub fn split_namespace(ns_name: &String) -> Result<(), Errcode> {
     // TODO do not exit for EINVAL error
     // unmount_path(&sys_path)?;
     // consider the case that a sysfs is not present
-    let stat_sys = statvfs(&sys_path)
-        .map_err(|e| {
+    let stat_sys = match statvfs(&sys_path) {
+        Ok(stat_sys) => stat_sys,
+        Err(e) => {
             log::error!("Can not stat sys: {}", e);
-    }).unwrap();
+            return Err(Errcode::SysError("Can not stat /sys".to_string(), e));
+        }
+    };
     if stat_sys.flags().contains(FsFlags::ST_RDONLY) {
         mount_flags.insert(MsFlags::MS_RDONLY);
     }
@@ -258,7 +275,7 @@ pub fn split_namespace(ns_name: &String) -> Result<(), Errcode> {
     // and remount a version of /sys that describes the network namespace
     if let Err(e) = mount::<PathBuf, PathBuf, str, PathBuf>(Some(&ns_name_path), &sys_path, Some("sysfs"), mount_flags, None) {
         log::error!("Can not remount /sys to namespace: {}", e);
-        return Err(Errcode::NamespacesError(format!("Can not remount /sys to namespace: {}", e)));
+        return Err(Errcode::SysError("Can not remount /sys to namespace".to_string(), e));
     }
 
     // call net_conf
@@ -1,21 +1,27 @@
 #![allow(dead_code)]
 use crate::errors::Errcode;
-use crate::namespaces::{open_namespace, run_in_namespace};
+use crate::namespaces::{open_namespace, remove_namespace, run_in_namespace};
+use crate::rollback::Rollback;
 
 use futures::TryStreamExt;
 use rtnetlink::{new_connection, AddressHandle, Handle};
 use std::net::{IpAddr, Ipv4Addr};
+use std::process::{Command, Stdio};
 use std::str::FromStr;
 
 static NETNS: &str = "/var/run/netns/";
 
-pub async fn prepare_net(ns_name: &String, veth_ip: &str, veth_2_ip: &str, subnet: u8) -> Result<(u32, u32), Errcode> {
+// The namespace and the veth pair are removed by rollback if a later step
+// fails, they disappear with the jail otherwise
+pub async fn prepare_net(ns_name: &String, veth_ip: &str, veth_2_ip: &str, subnet: u8, rollback: &mut Rollback) -> Result<(u32, u32), Errcode> {
     let (connection, _handle, _) = new_connection()?;
     tokio::spawn(connection);
 
     let ns_fd = open_namespace(&ns_name).await?;
+    let name = ns_name.clone();
+    rollback.push("network namespace", move || remove_namespace(&name));
 
-    let (veth_idx, veth_2_idx) = create_veth_pair(&ns_name, veth_ip, veth_2_ip, subnet).await?;
+    let (veth_idx, veth_2_idx) = create_veth_pair(&ns_name, veth_ip, veth_2_ip, subnet, rollback).await?;
 
     // moved to namespace crate
     join_veth_to_ns_fd(veth_2_idx, ns_fd).await?;
@@ -63,7 +69,7 @@ async fn create_bridge(name: String, bridge_ip: &str, subnet: u8) -> Result<u32,
     Ok(bridge_idx)
 }
 
-async fn create_veth_pair(veth_name: &String, veth_addr: &str, veth2_addr: &str, subnet: u8) -> Result<(u32, u32), Errcode> {
+async fn create_veth_pair(veth_name: &String, veth_addr: &str, veth2_addr: &str, subnet: u8, rollback: &mut Rollback) -> Result<(u32, u32), Errcode> {
     let (connection, handle, _) = new_connection()?;
     tokio::spawn(connection);
 
@@ -74,6 +80,9 @@ async fn create_veth_pair(veth_name: &String, veth_addr: &str, veth2_addr: &str,
         .map_err(|e| {
             Errcode::NetworkError(format!("Can not create veth interfaces: {}", e))
         })?;
+    // Undone with ip, the undo actions run outside of the tokio runtime
+    let name = veth.clone();
+    rollback.push("veth pair", move || delete_link(&name));
 
     let veth_idx = handle.link().get().match_name(veth.clone()).execute().try_next().await?
         .ok_or_else(|| Errcode::NetworkError(format!("Failed to get index for {}", veth)))?
@@ -86,26 +95,26 @@ async fn create_veth_pair(veth_name: &String, veth_addr: &str, veth2_addr: &str,
     // set master veth up
     handle.link().set(veth_idx).up().execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting veth {} up failed: {}", veth, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting veth {} up failed: {}", veth, e))
+    })?;
 
     let veth_ip_addr = IpAddr::V4(Ipv4Addr::from_str(veth_addr)?);
     AddressHandle::new(handle.clone()).add(veth_idx, veth_ip_addr, subnet).execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting addr {} to veth {} failed: {}", veth_addr, veth, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting addr {} to veth {} failed: {}", veth_addr, veth, e))
+    })?;
 
     let veth2_ip_addr = IpAddr::V4(Ipv4Addr::from_str(veth2_addr)?);
     AddressHandle::new(handle.clone()).add(veth_2_idx, veth2_ip_addr, subnet).execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting addr {} to veth {} failed: {}", veth2_addr, veth_2, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting addr {} to veth {} failed: {}", veth2_addr, veth_2, e))
+    })?;
 
     // set interface veth2 up
     handle.link().set(veth_2_idx).up().execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting veth with idx {} up failed: {}", veth_idx, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting veth with idx {} up failed: {}", veth_idx, e))
+    })?;
 
     // set lo interface up
     // TODO move to another function called in the namespace
@@ -145,6 +154,15 @@ pub async fn join_veth_to_ns_fd(veth_idx: u32, fd: i32) -> Result<(), Errcode> {
     Ok(())
 }
 
+// Deleting one end of a veth pair deletes the other one as well
+fn delete_link(name: &str) -> Result<(), Errcode> {
+    match Command::new("ip").args(["link", "del", name]).stdout(Stdio::null()).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Errcode::NetworkError(format!("ip link del {} exited with {}", name, status))),
+        Err(e) => Err(Errcode::IoError(format!("Can not run ip link del {}", name), e)),
+    }
+}
+
 // TODO continue configure address interface definition
 pub async fn setup_veth_peer(veth_idx: u32, ns_ip: &String, subnet: u8) -> Result<(), Errcode> {
     let (_connection, handle, _) = new_connection()?;
@@ -154,14 +172,14 @@ pub async fn setup_veth_peer(veth_idx: u32, ns_ip: &String, subnet: u8) -> Resul
     // Setup veth peer interface address
     AddressHandle::new(handle.clone()).add(veth_idx, veth2_addr, subnet).execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting addr {} to veth with index {} failed: {}", ns_ip, veth_idx, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting addr {} to veth with index {} failed: {}", ns_ip, veth_idx, e))
+    })?;
 
     // set interface veth2 up
     handle.link().set(veth_idx).up().execute().await
         .map_err(|e| {
-            Errcode::NetworkError(format!("Setting veth with idx {} up failed: {}", veth_idx, e));
-    }).unwrap();
+            Errcode::NetworkError(format!("Setting veth with idx {} up failed: {}", veth_idx, e))
+    })?;
 
     // set lo interface up
     let lo_idx = handle.link().get().match_name("lo".to_string()).execute().try_next().await?
@@ -170,7 +188,7 @@ pub async fn setup_veth_peer(veth_idx: u32, ns_ip: &String, subnet: u8) -> Resul
 
     handle.link().set(lo_idx).up().execute().await
         .map_err(|e| {Errcode::NetworkError(format!("Can not set lo interface up: {}", e))
-    }).unwrap();
+    })?;
 
     Ok(())
 }
@@ -1,8 +1,10 @@
+use crate::errors::Errcode;
+
 use nftables::{batch::Batch, expr, schema, stmt, types};
 
 const PREROUTING_CHAIN: &str = "PREROUTING";
 const DNAT_PRIORITY: i32 = -100;
-const NAT_TABLE: &str = "nat";
+pub const NAT_TABLE: &str = "nat";
 const TOR_DNS: u32 = 5353;
 const IFNAME: &str = "test";
 const PROXY_PORT: u32 = 9050;
@@ -113,8 +115,24 @@ fn example_ruleset() -> schema::Nftables {
     batch.to_nftables()
 }
 
-pub fn test_apply_ruleset() {
+pub fn test_apply_ruleset() -> Result<(), Errcode> {
     let ruleset = example_ruleset();
-    nftables::helper::apply_ruleset(&ruleset, None, None).unwrap();
+    if let Err(e) = nftables::helper::apply_ruleset(&ruleset, None, None) {
+        log::error!("Can not apply nftables ruleset: {:?}", e);
+        return Err(Errcode::NetworkError(format!("Can not apply nftables ruleset: {:?}", e)));
+    }
+    Ok(())
 }
 
+// Undo of test_apply_ruleset, the chain and its rules go with the table
+pub fn delete_ruleset() -> Result<(), Errcode> {
+    let mut batch = Batch::new();
+    batch.delete(schema::NfListObject::Table(schema::Table::new(
+        types::NfFamily::IP,
+        NAT_TABLE.to_string(),
+    )));
+    if let Err(e) = nftables::helper::apply_ruleset(&batch.to_nftables(), None, None) {
+        return Err(Errcode::NetworkError(format!("Can not delete nftables table {}: {:?}", NAT_TABLE, e)));
+    }
+    Ok(())
+}
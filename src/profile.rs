@@ -0,0 +1,74 @@
+// Jail profiles: security policies grouped under a name
+use crate::capabilities::CapabilityPolicy;
+use crate::errors::Errcode;
+use crate::landlock::LandlockPolicy;
+use crate::mountpoint::DevicePolicy;
+use crate::supervisor::SupervisorPolicy;
+use crate::syscalls::NetworkFilter;
+
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+
+// Profiles shipped with orjail, selected by name
+const BUILTIN_PROFILES: [(&str, &str); 2] = [
+    ("default", include_str!("../resources/profiles/default.json")),
+    ("supervised", include_str!("../resources/profiles/supervised.json")),
+];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub seccomp: SeccompPolicy,
+    pub supervisor: SupervisorPolicy,
+    pub capabilities: CapabilityPolicy,
+    pub landlock: LandlockPolicy,
+    pub devices: DevicePolicy,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeccompPolicy {
+    // Built-in name or path of an OCI seccomp profile
+    pub profile: String,
+    pub network: NetworkFilter,
+}
+
+impl Default for SeccompPolicy {
+    fn default() -> Self {
+        SeccompPolicy {
+            profile: "default".to_string(),
+            network: NetworkFilter::default(),
+        }
+    }
+}
+
+impl Profile {
+    // Load a built-in profile by name, otherwise read it from a JSON file
+    pub fn load(profile: &str) -> Result<Profile, Errcode> {
+        if let Some((_, contents)) = BUILTIN_PROFILES.iter().find(|(name, _)| *name == profile) {
+            log::debug!("Using built-in profile {}", profile);
+            return Profile::parse(contents, profile);
+        }
+
+        match read_to_string(profile) {
+            Ok(contents) => {
+                log::debug!("Using profile file {}", profile);
+                Profile::parse(&contents, profile)
+            }
+            Err(e) => {
+                log::error!("Profile {} is not built-in and can not be read: {}", profile, e);
+                Err(Errcode::IoError(format!("Can not read profile {}", profile), e))
+            }
+        }
+    }
+
+    fn parse(contents: &str, profile: &str) -> Result<Profile, Errcode> {
+        let parsed: Profile = serde_json::from_str(contents).map_err(|e| {
+            Errcode::ProfileError(format!("Can not parse profile {}: {}", profile, e))
+        })?;
+        parsed.supervisor.validate()?;
+        parsed.capabilities.keep_set()?;
+        parsed.devices.validate()?;
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,225 @@
+// Terminal of interactive jails: the container gets the slave of a new pty as
+// controlling terminal, and the parent relays it to its own terminal
+use crate::errors::Errcode;
+use crate::ipc::send_fd;
+use crate::logs::RotatingLog;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::signal::{pthread_sigmask, SigSet, SigmaskHow, Signal};
+use nix::sys::signalfd::SignalFd;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::{dup2, isatty, read, setsid};
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Write};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread::{spawn, JoinHandle};
+
+// Ctrl-], as in telnet
+pub const DETACH_KEY: u8 = 0x1d;
+
+pub struct Pty {
+    pub master: OwnedFd,
+    pub slave: OwnedFd,
+}
+
+// The host terminal is put back as it was when this is dropped
+pub struct TerminalGuard {
+    saved: Termios,
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Err(e) = tcsetattr(stdin(), SetArg::TCSANOW, &self.saved) {
+            log::error!("Can not restore the terminal: {}", e);
+        }
+    }
+}
+
+// A pty is only needed when orjail itself runs in a terminal, or when the
+// jail is detached so that it can be attached later
+pub fn open_pty(detach: bool) -> Result<Option<Pty>, Errcode> {
+    if !detach && !isatty(stdin().as_raw_fd()).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let pty = match openpty(None, None) {
+        Ok(pty) => pty,
+        Err(e) => return Err(Errcode::SysError("Can not allocate a pty".to_string(), e)),
+    };
+    // Only the container init keeps the slave, through its standard streams
+    for fd in [&pty.master, &pty.slave] {
+        if let Err(e) = fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            return Err(Errcode::SysError("Can not set close on exec on the pty".to_string(), e));
+        }
+    }
+    copy_window_size(stdin().as_raw_fd(), pty.master.as_raw_fd());
+    Ok(Some(Pty { master: pty.master, slave: pty.slave }))
+}
+
+// Called in the container: leave the session of the host terminal, so that it
+// can not be reached with TIOCSTI, and take the pty slave as stdio
+pub fn set_controlling_terminal(slave: RawFd) -> Result<(), Errcode> {
+    if let Err(e) = setsid() {
+        return Err(Errcode::SysError("Can not create a new session".to_string(), e));
+    }
+    if unsafe { libc::ioctl(slave, libc::TIOCSCTTY, 0) } < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Errcode::IoError("Can not set the controlling terminal".to_string(), e));
+    }
+    for fd in 0..3 {
+        if let Err(e) = dup2(slave, fd) {
+            return Err(Errcode::SysError(format!("Can not redirect {} to the pty", fd), e));
+        }
+    }
+    Ok(())
+}
+
+// Put the host terminal in raw mode and relay it to the pty until the
+// container exits, or until DETACH_KEY is typed when attached to a detached
+// jail. Window size changes are forwarded with SIGWINCH.
+pub fn start_relay(master: OwnedFd, detachable: bool) -> Result<(TerminalGuard, JoinHandle<()>), Errcode> {
+    let saved = match tcgetattr(stdin()) {
+        Ok(termios) => termios,
+        Err(e) => return Err(Errcode::SysError("Can not read the terminal attributes".to_string(), e)),
+    };
+    let mut raw = saved.clone();
+    cfmakeraw(&mut raw);
+    if let Err(e) = tcsetattr(stdin(), SetArg::TCSANOW, &raw) {
+        return Err(Errcode::SysError("Can not set the terminal in raw mode".to_string(), e));
+    }
+    let guard = TerminalGuard { saved };
+
+    // Blocked before the relay thread is spawned, so that every thread but
+    // the signalfd leaves SIGWINCH alone
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGWINCH);
+    if let Err(e) = pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), None) {
+        return Err(Errcode::SysError("Can not block SIGWINCH".to_string(), e));
+    }
+    let winch = match SignalFd::new(&mask) {
+        Ok(fd) => fd,
+        Err(e) => return Err(Errcode::SysError("Can not create signalfd".to_string(), e)),
+    };
+
+    copy_window_size(stdin().as_raw_fd(), master.as_raw_fd());
+    let handle = spawn(move || relay(File::from(master), winch, detachable));
+    Ok((guard, handle))
+}
+
+fn relay(mut master: File, mut winch: SignalFd, detachable: bool) {
+    let input = stdin();
+    let mut buf = [0u8; 4096];
+    let mut input_open = true;
+    loop {
+        let mut fds = vec![PollFd::new(&master, PollFlags::POLLIN), PollFd::new(&winch, PollFlags::POLLIN)];
+        if input_open {
+            fds.push(PollFd::new(&input, PollFlags::POLLIN));
+        }
+        match poll(&mut fds, -1) {
+            Ok(_) => {},
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                log::error!("Can not poll the terminal: {}", e);
+                break;
+            }
+        }
+        let events: Vec<PollFlags> = fds.iter().map(|fd| fd.revents().unwrap_or(PollFlags::empty())).collect();
+
+        if events[1].contains(PollFlags::POLLIN) {
+            let _ = winch.read_signal();
+            copy_window_size(input.as_raw_fd(), master.as_raw_fd());
+        }
+
+        if input_open && events[2].intersects(PollFlags::POLLIN | PollFlags::POLLHUP) {
+            // Read without the buffer of Stdin, which poll can not see
+            match read(input.as_raw_fd(), &mut buf) {
+                Ok(0) | Err(_) => input_open = false,
+                Ok(n) => {
+                    let detach = if detachable { buf[..n].iter().position(|c| *c == DETACH_KEY) } else { None };
+                    if master.write_all(&buf[..detach.unwrap_or(n)]).is_err() || detach.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // The slave is closed, EIO, once every process of the container is gone
+        if events[0].contains(PollFlags::POLLIN) {
+            match master.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let mut output = stdout().lock();
+                    if output.write_all(&buf[..n]).and_then(|_| output.flush()).is_err() {
+                        break;
+                    }
+                }
+            }
+        } else if events[0].intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+            break;
+        }
+    }
+}
+
+// Detached jails: the output goes to their log, and the pty is handed over to
+// orjailrs attach through the attach socket. Nothing is logged while a client
+// is attached, only one can be at a time.
+pub fn start_log_relay(master: OwnedFd, mut log: RotatingLog, listener: UnixListener) -> JoinHandle<()> {
+    spawn(move || {
+        let mut master = File::from(master);
+        let mut buf = [0u8; 4096];
+        let mut client: Option<UnixStream> = None;
+        loop {
+            let master_events = if client.is_some() { PollFlags::empty() } else { PollFlags::POLLIN };
+            let mut fds = vec![PollFd::new(&master, master_events), PollFd::new(&listener, PollFlags::POLLIN)];
+            if let Some(client) = client.as_ref() {
+                fds.push(PollFd::new(client, PollFlags::POLLIN));
+            }
+            match poll(&mut fds, -1) {
+                Ok(_) => {},
+                Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    log::error!("Can not poll the pty of the jail: {}", e);
+                    break;
+                }
+            }
+            let events: Vec<PollFlags> = fds.iter().map(|fd| fd.revents().unwrap_or(PollFlags::empty())).collect();
+
+            // Attached clients do not send anything, they only hang up
+            if client.is_some() && !events[2].is_empty() {
+                log::debug!("Client detached from the jail");
+                client = None;
+            }
+
+            if events[1].contains(PollFlags::POLLIN) {
+                if let Ok((stream, _)) = listener.accept() {
+                    if client.is_none() && send_fd(stream.as_raw_fd(), master.as_raw_fd()).is_ok() {
+                        log::debug!("Client attached to the jail");
+                        client = Some(stream);
+                    }
+                }
+            }
+
+            if events[0].contains(PollFlags::POLLIN) {
+                match master.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => log.write(&buf[..n]),
+                }
+            } else if events[0].intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+                break;
+            }
+        }
+    })
+}
+
+fn copy_window_size(from: RawFd, to: RawFd) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::ioctl(from, libc::TIOCGWINSZ, &mut size) == 0 {
+            libc::ioctl(to, libc::TIOCSWINSZ, &size);
+        }
+    }
+}
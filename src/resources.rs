@@ -1,58 +1,274 @@
-#![allow(dead_code)]
-use rlimit::{setrlimit, Resource};
-use cgroups_rs::cgroup_builder::CgroupBuilder;
-use cgroups_rs::hierarchies::V2;
-use cgroups_rs::{MaxValue, CgroupPid};
-use nix::unistd::Pid;
-use std::fs::{canonicalize, remove_dir};
-use std::convert::TryInto;
-
+// Resource limits of a jail with cgroup v2, without privileges: the cgroup of
+// the jail is created in the subtree that systemd delegates to the user, as
+// <delegated root>/orjail/<hostname>, and the parent moves the child in it
+// before it sets up the jail and executes the command. No cgroup is created
+// when no limit is set.
 use crate::errors::Errcode;
 
-const KMEM_LIMIT: i64 = 1024 * 1024 * 1024;
-const MEM_LIMIT: i64 = KMEM_LIMIT;
-const MAX_PID: MaxValue = MaxValue::Value(256);
-const NOFILE_RLIMIT: u64 = 256;
-
-pub fn restrict_resources(hostname: &String, pid: Pid) -> Result<(), Errcode>{
-    log::debug!("Restricting resources for hostname {}", hostname);
-    // Cgroups
-    let cgs = CgroupBuilder::new(hostname)
-        // Allocate less CPU time than other processes
-        .cpu().shares(256).done()
-        // Limiting the memory usage to 1 GiB
-        // The user can limit it to less than this, never increase above 1Gib
-        .memory().kernel_memory_limit(KMEM_LIMIT).memory_hard_limit(MEM_LIMIT).done()
-        // This process can only create a maximum of 64 child processes
-        .pid().maximum_number_of_processes(MAX_PID).done()
-        // Give an access priority to block IO lower than the system
-        .blkio().weight(50).done()
-        .build(Box::new(V2::new()));
-    // We apply the cgroups rules to the child process we just created
-    let pid : u64 = pid.as_raw().try_into().unwrap();
-    if let Err(e) = cgs.add_task(CgroupPid::from(pid)) {
-        return Err(Errcode::ResourcesError(format!("Error during cgroups conf for PID {pid}: {e}")));
-    };
-    // Rlimit
-    // Can create only 64 file descriptors
-    if let Err(e) = setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT){
-        return Err(Errcode::ResourcesError(format!("Cgroups: setrlimit returned error {e}")));
+use nix::unistd::{getuid, Pid};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir, read_to_string, remove_dir, write};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const ORJAIL_CGROUP: &str = "orjail";
+const CPU_PERIOD: u64 = 100_000;
+// The processes of the jail may still be exiting when it is removed
+const REMOVE_RETRIES: u32 = 20;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    // memory.max, in bytes
+    pub memory_max: Option<u64>,
+    // pids.max
+    pub pids_max: Option<u64>,
+    // cpu.max, quota and period in microseconds
+    pub cpu_max: Option<(u64, u64)>,
+    // io.weight, from 1 to 10000
+    pub io_weight: Option<u16>,
+}
+
+impl ResourceLimits {
+    // A number of bytes, with an optional K, M, G or T suffix
+    pub fn parse_size(size: &str) -> Result<u64, Errcode> {
+        let (number, unit) = match size.char_indices().last() {
+            Some((i, 'K')) => (&size[..i], 1 << 10),
+            Some((i, 'M')) => (&size[..i], 1 << 20),
+            Some((i, 'G')) => (&size[..i], 1 << 30),
+            Some((i, 'T')) => (&size[..i], 1 << 40),
+            _ => (size, 1),
+        };
+        match number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)) {
+            Some(bytes) => Ok(bytes),
+            None => Err(Errcode::ArgumentInvalid(format!("memory-max {}", size))),
+        }
+    }
+
+    // quota[/period] in microseconds, the period defaults to 100ms
+    pub fn parse_cpu_max(cpu_max: &str) -> Result<(u64, u64), Errcode> {
+        let mut fields = cpu_max.splitn(2, '/');
+        let quota = fields.next().and_then(|quota| quota.parse::<u64>().ok());
+        let period = match fields.next() {
+            Some(period) => period.parse::<u64>().ok(),
+            None => Some(CPU_PERIOD),
+        };
+        match (quota, period) {
+            (Some(quota), Some(period)) => Ok((quota, period)),
+            _ => Err(Errcode::ArgumentInvalid(format!("cpu-max {}", cpu_max))),
+        }
     }
-    Ok(())
+
+    fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.pids_max.is_none() && self.cpu_max.is_none() && self.io_weight.is_none()
+    }
+
+    // Controller, file and value of each limit that is set
+    fn settings(&self) -> Result<Vec<(&'static str, &'static str, String)>, Errcode> {
+        let mut settings = vec![];
+        if let Some(memory_max) = self.memory_max {
+            settings.push(("memory", "memory.max", memory_max.to_string()));
+        }
+        if let Some(pids_max) = self.pids_max {
+            settings.push(("pids", "pids.max", pids_max.to_string()));
+        }
+        if let Some((quota, period)) = self.cpu_max {
+            // Bounds of the kernel
+            if quota < 1000 || !(1000..=1_000_000).contains(&period) {
+                return Err(Errcode::ResourcesError(format!("cpu.max {quota} {period} out of range, quota and period are at least 1000us, period at most 1s")));
+            }
+            settings.push(("cpu", "cpu.max", format!("{quota} {period}")));
+        }
+        if let Some(io_weight) = self.io_weight {
+            if !(1..=10000).contains(&io_weight) {
+                return Err(Errcode::ResourcesError(format!("io.weight {io_weight} out of range, from 1 to 10000")));
+            }
+            settings.push(("io", "io.weight", format!("default {io_weight}")));
+        }
+        Ok(settings)
+    }
+}
+
+pub struct Cgroup {
+    path: PathBuf,
 }
 
-pub fn clean_cgroups(hostname: &String) -> Result<(), Errcode>{
-    log::debug!("Cleaning cgroups");
-    match canonicalize(format!("/sys/fs/cgroup/{}/", hostname)){
-        Ok(d) => {
-            if let Err(e) = remove_dir(&d) {
-                return Err(Errcode::ResourcesError(format!("Error while trying to delete dir {}: {}", d.to_str().unwrap(), e)));
+impl Cgroup {
+    // Returns None when there is nothing to limit
+    pub fn create(hostname: &str, limits: &ResourceLimits) -> Result<Option<Cgroup>, Errcode> {
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let settings = limits.settings()?;
+        let root = delegated_root()?;
+        log::debug!("Creating cgroup of {} in delegated subtree {}", hostname, root.display());
+
+        let available = read_cgroup_file(&root, "cgroup.controllers")?;
+        let mut controllers: Vec<&str> = settings.iter().map(|(controller, _, _)| *controller).collect();
+        controllers.dedup();
+        if let Some(missing) = controllers.iter().find(|controller| !available.split_whitespace().any(|c| c == **controller)) {
+            log::error!("The {} controller is not delegated to the user, add it to Delegate= of user@.service", missing);
+            return Err(Errcode::ResourcesError(format!("Controller {} not available in {}", missing, root.display())));
+        }
+        let enable = controllers.iter().map(|controller| format!("+{controller}")).collect::<Vec<_>>().join(" ");
+
+        // Only cgroups without processes can enable controllers for their
+        // children, the jails share an orjail cgroup for that
+        let parent = root.join(ORJAIL_CGROUP);
+        write_cgroup_file(&root, "cgroup.subtree_control", &enable)?;
+        if let Err(e) = create_dir(&parent) {
+            if e.kind() != ErrorKind::AlreadyExists {
+                return Err(Errcode::IoError(format!("Can not create cgroup {}", parent.display()), e));
+            }
+        }
+        write_cgroup_file(&parent, "cgroup.subtree_control", &enable)?;
+
+        let path = parent.join(hostname);
+        if let Err(e) = create_dir(&path) {
+            return Err(Errcode::IoError(format!("Can not create cgroup {}", path.display()), e));
+        }
+        // Removed when dropped on error
+        let cgroup = Cgroup { path };
+        for (_, file, value) in settings.iter() {
+            write_cgroup_file(&cgroup.path, file, value)?;
+        }
+        Ok(Some(cgroup))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn add(&self, pid: Pid) -> Result<(), Errcode> {
+        join_cgroup(&self.path, pid)
+    }
+
+    fn remove(&self) -> Result<(), Errcode> {
+        let mut retries = 0;
+        loop {
+            match remove_dir(&self.path) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) && retries < REMOVE_RETRIES => {
+                    retries += 1;
+                    sleep(Duration::from_millis(50));
+                }
+                Err(e) => return Err(Errcode::IoError(format!("Can not remove cgroup {}", self.path.display()), e)),
             }
-        },
-        Err(e) => {
-            log::error!("Error while canonicalize path: {}", e);
-            return Err(Errcode::ResourcesError(format!("Error while canonicalize path: {}", e)));
         }
     }
-    Ok(())
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.remove() {
+            log::error!("{}", e);
+        }
+    }
+}
+
+// Also used by orjailrs exec, for its command to share the limits of the jail
+pub fn join_cgroup(path: &Path, pid: Pid) -> Result<(), Errcode> {
+    write_cgroup_file(path, "cgroup.procs", &pid.to_string())
+}
+
+// The highest ancestor of the cgroup of orjail that belongs to the user, like
+// user@<uid>.service. Moving a process between two cgroups needs write access
+// to their common ancestor, which holds for the cgroups below it.
+fn delegated_root() -> Result<PathBuf, Errcode> {
+    let own = match read_to_string("/proc/self/cgroup") {
+        Ok(content) => content.lines().find_map(|line| line.strip_prefix("0::").map(str::to_string)),
+        Err(e) => return Err(Errcode::IoError("Can not read /proc/self/cgroup".to_string(), e)),
+    };
+    let own = match own {
+        Some(own) if Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() => own,
+        _ => return Err(Errcode::NotSupported("Resource limits need the cgroup v2 unified hierarchy".to_string())),
+    };
+
+    let uid = getuid().as_raw();
+    let mut root = None;
+    let mut path = PathBuf::from(CGROUP_ROOT).join(own.trim_start_matches('/'));
+    while path != Path::new(CGROUP_ROOT) {
+        if path.metadata().map(|meta| meta.uid() == uid).unwrap_or(false) {
+            root = Some(path.clone());
+        }
+        if !path.pop() {
+            break;
+        }
+    }
+    match root {
+        Some(root) => Ok(root),
+        None => {
+            log::error!("The cgroup {} of orjail is not delegated to the user", own);
+            log::error!("Run it in a systemd user scope, for example: systemd-run --user --scope orjailrs ...");
+            Err(Errcode::ResourcesError(format!("No delegated cgroup subtree above {}", own)))
+        }
+    }
+}
+
+fn read_cgroup_file(cgroup: &Path, file: &str) -> Result<String, Errcode> {
+    match read_to_string(cgroup.join(file)) {
+        Ok(content) => Ok(content),
+        Err(e) => Err(Errcode::ResourcesError(format!("Can not read {}: {e}", cgroup.join(file).display()))),
+    }
+}
+
+fn write_cgroup_file(cgroup: &Path, file: &str, value: &str) -> Result<(), Errcode> {
+    match write(cgroup.join(file), value) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Errcode::ResourcesError(format!("Can not write {} to {}: {e}", value, cgroup.join(file).display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(ResourceLimits::parse_size("4096").unwrap(), 4096);
+        assert_eq!(ResourceLimits::parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(ResourceLimits::parse_size("256M").unwrap(), 256 << 20);
+        assert_eq!(ResourceLimits::parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(ResourceLimits::parse_size("1T").unwrap(), 1 << 40);
+        assert_eq!(ResourceLimits::parse_size("18446744073709551615").unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        // cgroup's "max" is the same as no limit, which is the default
+        for size in ["max", "", "K", "1.5G", "-1", "1KB", "18446744073709551616", "16777216T", "17179869184G"] {
+            assert!(ResourceLimits::parse_size(size).is_err(), "{size}");
+        }
+    }
+
+    #[test]
+    fn cpu_max_quota_and_period() {
+        assert_eq!(ResourceLimits::parse_cpu_max("50000").unwrap(), (50000, CPU_PERIOD));
+        assert_eq!(ResourceLimits::parse_cpu_max("25000/50000").unwrap(), (25000, 50000));
+        for cpu_max in ["max", "max/100000", "", "/100000", "50000/", "50000/100000/1", "18446744073709551616"] {
+            assert!(ResourceLimits::parse_cpu_max(cpu_max).is_err(), "{cpu_max}");
+        }
+    }
+
+    #[test]
+    fn settings_within_kernel_bounds() {
+        let limits = |cpu_max, io_weight| ResourceLimits { cpu_max, io_weight, ..Default::default() };
+        assert!(limits(Some((1000, 1000)), Some(1)).settings().is_ok());
+        assert!(limits(Some((u64::MAX, 1_000_000)), Some(10000)).settings().is_ok());
+        assert!(limits(Some((999, CPU_PERIOD)), None).settings().is_err());
+        assert!(limits(Some((50000, 999)), None).settings().is_err());
+        assert!(limits(Some((50000, 1_000_001)), None).settings().is_err());
+        assert!(limits(None, Some(0)).settings().is_err());
+        assert!(limits(None, Some(10001)).settings().is_err());
+    }
+
+    #[test]
+    fn empty_limits_create_no_cgroup() {
+        assert!(ResourceLimits::default().is_empty());
+        assert!(Cgroup::create("orjail-test", &ResourceLimits::default()).unwrap().is_none());
+    }
 }
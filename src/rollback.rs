@@ -0,0 +1,46 @@
+// Setup of a jail as a sequence of steps: each step that created something
+// registers how to undo it. Unless the setup is committed, the undo actions
+// run in reverse order when the rollback is dropped, so a failure at any step
+// removes what the previous ones left behind.
+use crate::errors::Errcode;
+
+type Undo = Box<dyn FnOnce() -> Result<(), Errcode>>;
+
+pub struct Rollback {
+    steps: Vec<(String, Undo)>,
+}
+
+impl Rollback {
+    pub fn new() -> Rollback {
+        Rollback { steps: vec![] }
+    }
+
+    // Called once the step succeeded
+    pub fn push<F>(&mut self, step: &str, undo: F)
+    where
+        F: FnOnce() -> Result<(), Errcode> + 'static,
+    {
+        self.steps.push((step.to_string(), Box::new(undo)));
+    }
+
+    // The setup succeeded, what it created is now cleaned up with the jail
+    pub fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    // Errors are only logged, the remaining steps are undone anyway
+    pub fn unwind(&mut self) {
+        while let Some((step, undo)) = self.steps.pop() {
+            log::debug!("Undoing setup step {}", step);
+            if let Err(e) = undo() {
+                log::error!("Can not undo setup step {}: {}", step, e);
+            }
+        }
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        self.unwind();
+    }
+}
@@ -0,0 +1,242 @@
+// Seccomp profiles, in the OCI runtime-spec format used by runc and docker
+use crate::audit::AuditRecord;
+use crate::errors::Errcode;
+
+use libseccomp::{ScmpAction, ScmpArch, ScmpArgCompare, ScmpCompareOp, ScmpSyscall};
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+const EPERM: u32 = 1;
+
+// Profiles shipped with orjail, selected by name
+const BUILTIN_SECCOMP_PROFILES: [(&str, &str); 4] = [
+    ("default", include_str!("../resources/seccomp/default.json")),
+    ("browser", include_str!("../resources/seccomp/browser.json")),
+    ("cli-tool", include_str!("../resources/seccomp/cli-tool.json")),
+    ("permissive", include_str!("../resources/seccomp/permissive.json")),
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompProfile {
+    pub default_action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    pub names: Vec<String>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<SyscallArg>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String,
+}
+
+impl SeccompProfile {
+    // Load a built-in profile by name, otherwise read it from a JSON file
+    pub fn load(profile: &str) -> Result<SeccompProfile, Errcode> {
+        if let Some((_, contents)) = BUILTIN_SECCOMP_PROFILES.iter().find(|(name, _)| *name == profile) {
+            log::debug!("Using built-in seccomp profile {}", profile);
+            return SeccompProfile::parse(contents, profile);
+        }
+
+        match read_to_string(profile) {
+            Ok(contents) => {
+                log::debug!("Using seccomp profile file {}", profile);
+                SeccompProfile::parse(&contents, profile)
+            }
+            Err(e) => {
+                log::error!("Seccomp profile {} is not built-in and can not be read: {}", profile, e);
+                Err(Errcode::IoError(format!("Can not read seccomp profile {}", profile), e))
+            }
+        }
+    }
+
+    fn parse(contents: &str, profile: &str) -> Result<SeccompProfile, Errcode> {
+        let parsed: SeccompProfile = serde_json::from_str(contents).map_err(|e| {
+            Errcode::ProfileError(format!("Can not parse seccomp profile {}: {}", profile, e))
+        })?;
+
+        // Validate everything now, so that a broken profile is reported
+        // before the container gets created
+        parsed.default_action()?;
+        parsed.arches()?;
+        for rule in parsed.syscalls.iter() {
+            rule.action()?;
+            rule.comparators()?;
+        }
+        Ok(parsed)
+    }
+
+    pub fn default_action(&self) -> Result<ScmpAction, Errcode> {
+        parse_action(&self.default_action, self.default_errno_ret)
+    }
+
+    // Extend the profile so that the audited syscalls would be allowed: they
+    // are removed from the rules refusing them and, for allowlists, added as
+    // allowed. Syscalls the profile restricts by argument are only allowed with
+    // the audited values of those arguments.
+    pub fn suggest(&self, records: &[AuditRecord]) -> SeccompProfile {
+        let mut suggested = self.clone();
+        for rule in suggested.syscalls.iter_mut() {
+            if !is_allow(&rule.action) {
+                rule.names.retain(|name| !records.iter().any(|r| &r.syscall == name));
+            }
+        }
+        suggested.syscalls.retain(|rule| !rule.names.is_empty());
+
+        if is_allow(&self.default_action) {
+            return suggested;
+        }
+
+        let mut names: Vec<String> = vec![];
+        let mut conditional: Vec<SyscallRule> = vec![];
+        for record in records.iter() {
+            let mut indexes: Vec<u32> = self.syscalls.iter()
+                .filter(|rule| is_allow(&rule.action) && rule.names.contains(&record.syscall))
+                .flat_map(|rule| rule.args.iter().map(|arg| arg.index))
+                .filter(|index| (*index as usize) < record.args.len())
+                .collect();
+            indexes.sort_unstable();
+            indexes.dedup();
+
+            if indexes.is_empty() {
+                if !names.contains(&record.syscall) {
+                    names.push(record.syscall.clone());
+                }
+                continue;
+            }
+            let rule = SyscallRule {
+                names: vec![record.syscall.clone()],
+                action: "SCMP_ACT_ALLOW".to_string(),
+                errno_ret: None,
+                args: indexes.iter().map(|index| SyscallArg {
+                    index: *index,
+                    value: record.args[*index as usize],
+                    value_two: 0,
+                    op: "SCMP_CMP_EQ".to_string(),
+                }).collect(),
+            };
+            if !conditional.contains(&rule) {
+                conditional.push(rule);
+            }
+        }
+
+        if !names.is_empty() {
+            names.sort();
+            suggested.syscalls.push(SyscallRule {
+                names,
+                action: "SCMP_ACT_ALLOW".to_string(),
+                errno_ret: None,
+                args: vec![],
+            });
+        }
+        suggested.syscalls.extend(conditional);
+        suggested
+    }
+
+    pub fn has_rule(&self, name: &str) -> bool {
+        self.syscalls.iter().any(|rule| rule.names.iter().any(|n| n == name))
+    }
+
+    // Whether the profile allows the syscall whatever its arguments
+    pub fn allows(&self, name: &str) -> bool {
+        if !self.has_rule(name) {
+            return is_allow(&self.default_action);
+        }
+        self.syscalls.iter().any(|rule| rule.names.iter().any(|n| n == name) && is_allow(&rule.action) && rule.args.is_empty())
+    }
+
+    pub fn arches(&self) -> Result<Vec<ScmpArch>, Errcode> {
+        self.architectures.iter().map(|arch| {
+            ScmpArch::from_str(arch).map_err(|e| {
+                Errcode::ProfileError(format!("Unknown seccomp architecture {}: {}", arch, e))
+            })
+        }).collect()
+    }
+}
+
+impl SyscallRule {
+    pub fn action(&self) -> Result<ScmpAction, Errcode> {
+        parse_action(&self.action, self.errno_ret)
+    }
+
+    // Names that are not known to libseccomp, e.g. syscalls newer than the
+    // installed library, are skipped as runc does
+    pub fn syscalls(&self) -> Vec<(&str, ScmpSyscall)> {
+        self.names.iter().filter_map(|name| {
+            match ScmpSyscall::from_name(name) {
+                Ok(sc) => Some((name.as_str(), sc)),
+                Err(_) => {
+                    log::debug!("Skipping unknown syscall {}", name);
+                    None
+                }
+            }
+        }).collect()
+    }
+
+    // All the conditions of a rule must match. Conditions on the same argument
+    // can not be combined by libseccomp, so as runc does each of them becomes
+    // a separate rule that matches on its own.
+    pub fn comparators(&self) -> Result<Vec<Vec<ScmpArgCompare>>, Errcode> {
+        let mut comps = vec![];
+        for arg in self.args.iter() {
+            comps.push(arg.comparator()?);
+        }
+
+        let mut indexes: Vec<u32> = self.args.iter().map(|arg| arg.index).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        if indexes.len() == self.args.len() {
+            Ok(vec![comps])
+        } else {
+            Ok(comps.into_iter().map(|comp| vec![comp]).collect())
+        }
+    }
+}
+
+impl SyscallArg {
+    fn comparator(&self) -> Result<ScmpArgCompare, Errcode> {
+        let op = match self.op.as_str() {
+            // For masked comparisons value is the mask, valueTwo the datum
+            "SCMP_CMP_MASKED_EQ" => return Ok(ScmpArgCompare::new(self.index, ScmpCompareOp::MaskedEqual(self.value), self.value_two)),
+            other => ScmpCompareOp::from_str(other).map_err(|e| {
+                Errcode::ProfileError(format!("Unknown seccomp operator {}: {}", other, e))
+            })?,
+        };
+        Ok(ScmpArgCompare::new(self.index, op, self.value))
+    }
+}
+
+fn is_allow(action: &str) -> bool {
+    action == "SCMP_ACT_ALLOW" || action == "SCMP_ACT_LOG"
+}
+
+fn parse_action(action: &str, errno_ret: Option<u32>) -> Result<ScmpAction, Errcode> {
+    let value = match action {
+        "SCMP_ACT_ERRNO" => Some(errno_ret.unwrap_or(EPERM) as i32),
+        "SCMP_ACT_TRACE" => Some(errno_ret.unwrap_or(EPERM) as i32),
+        _ => None,
+    };
+    ScmpAction::from_str(action, value).map_err(|e| {
+        Errcode::ProfileError(format!("Unknown seccomp action {}: {}", action, e))
+    })
+}
@@ -0,0 +1,102 @@
+// Signals received by the orjail parent: they are forwarded to the jail
+// instead of killing the parent, so that the cleanup always runs
+use crate::errors::Errcode;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{kill, pthread_sigmask, SigSet, SigmaskHow, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::time::{Duration, Instant};
+
+const TERMINATION_SIGNALS: [Signal; 4] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGQUIT];
+// Time left to the jail to exit after a forwarded signal, before SIGKILL
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+pub struct ParentSignals {
+    fd: SignalFd,
+    previous: SigSet,
+}
+
+impl ParentSignals {
+    // Blocks the signals in the calling thread, threads spawned afterwards
+    // inherit the mask so that only the signalfd receives them
+    pub fn block() -> Result<ParentSignals, Errcode> {
+        let mut mask = SigSet::empty();
+        for signal in TERMINATION_SIGNALS.iter().chain([Signal::SIGCHLD].iter()) {
+            mask.add(*signal);
+        }
+        let mut previous = SigSet::empty();
+        if let Err(e) = pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut previous)) {
+            return Err(Errcode::SysError("Can not block signals".to_string(), e));
+        }
+        match SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK) {
+            Ok(fd) => Ok(ParentSignals { fd, previous }),
+            Err(e) => Err(Errcode::SysError("Can not create signalfd".to_string(), e)),
+        }
+    }
+
+    // The mask before the signals were blocked, for the children to restore
+    pub fn previous(&self) -> SigSet {
+        self.previous
+    }
+
+    // Wait for the jail to exit. The first termination signal is forwarded
+    // to it, a second one or the end of the grace period kills it.
+    pub fn wait_child(&mut self, child: Pid) -> Result<WaitStatus, Errcode> {
+        log::debug!("Waiting for child (pid {}) to finish", child);
+        let mut deadline: Option<Instant> = None;
+        loop {
+            // The child may have exited before the signals were blocked
+            match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::EINTR) => {},
+                Ok(status) => return Ok(status),
+                Err(e) => {
+                    log::error!("Error while waiting for child to finish: {:?}", e);
+                    return Err(Errcode::SysError("Error while waiting for child to finish".to_string(), e));
+                }
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis() as i32,
+                None => -1,
+            };
+            let mut fds = [PollFd::new(&self.fd, PollFlags::POLLIN)];
+            match poll(&mut fds, timeout) {
+                Ok(0) => {
+                    log::warn!("The jail did not exit {} seconds after the signal, killing it", GRACE_PERIOD.as_secs());
+                    kill_child(child, Signal::SIGKILL);
+                    deadline = None;
+                    continue;
+                }
+                Ok(_) | Err(Errno::EINTR) => {},
+                Err(e) => return Err(Errcode::SysError("Can not poll signalfd".to_string(), e)),
+            }
+
+            while let Ok(Some(info)) = self.fd.read_signal() {
+                let signal = match Signal::try_from(info.ssi_signo as i32) {
+                    Ok(Signal::SIGCHLD) | Err(_) => continue,
+                    Ok(signal) => signal,
+                };
+                if deadline.is_some() {
+                    log::warn!("Received {} again, killing the jail", signal);
+                    kill_child(child, Signal::SIGKILL);
+                } else {
+                    log::info!("Received {}, forwarding it to the jail", signal);
+                    kill_child(child, signal);
+                    deadline = Some(Instant::now() + GRACE_PERIOD);
+                }
+            }
+        }
+    }
+}
+
+// Without --init the command is PID 1 of the jail, and the kernel drops the
+// signals it has no handler for: the grace period then ends with SIGKILL
+fn kill_child(child: Pid, signal: Signal) {
+    match kill(child, signal) {
+        Ok(_) | Err(Errno::ESRCH) => {},
+        Err(e) => log::error!("Can not send {} to the jail: {}", signal, e),
+    }
+}
@@ -1,8 +1,12 @@
 use crate::errors::Errcode;
+use std::fs::File;
+use std::io::Read;
 use std::process::{Child, Command, Stdio};
 use std::sync::{Arc, Mutex};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::PathBuf;
-use nix::unistd::Pid;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{pipe, Pid};
 
 pub struct SlirpProcess {
     pub process: Child,
@@ -11,29 +15,47 @@ pub struct SlirpProcess {
 pub type SlirpWrapper = Arc<Mutex<SlirpProcess>>;
 
 impl SlirpProcess {
-    // TODO continue from here to copy tor impl.
+    // Returns once tap0 is configured in the network namespace of pid, which
+    // slirp4netns reports on its ready fd
     pub fn new(pid: Pid, slirp_path: &PathBuf) -> Result<SlirpProcess, Errcode> {
         let pid_str = format!("{}", pid.as_raw());
-        // TODO catch error when spawning slirp4netns
+        // Only the write end is inherited by slirp4netns
+        let (ready_read, ready_write) = match pipe() {
+            Ok((read_end, write_end)) => unsafe { (File::from_raw_fd(read_end), OwnedFd::from_raw_fd(write_end)) },
+            Err(e) => return Err(Errcode::SysError("Can not create the ready pipe of slirp".to_string(), e)),
+        };
+        if let Err(e) = fcntl(ready_read.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            return Err(Errcode::SysError("Can not create the ready pipe of slirp".to_string(), e));
+        }
+        let ready_fd = format!("--ready-fd={}", ready_write.as_raw_fd());
         let slirp_process = Command::new(slirp_path.as_os_str())
-                        .args(["--configure", "--mtu=65520", "--disable-host-loopback", &pid_str, "tap0"])
+                        .args(["--configure", "--mtu=65520", "--disable-host-loopback", &ready_fd, &pid_str, "tap0"])
                         .stdout(Stdio::null())
                         .spawn();
+        drop(ready_write);
 
-        match slirp_process {
-            Ok(child) => Ok(SlirpProcess { process: child }),
+        let slirp = match slirp_process {
+            Ok(child) => SlirpProcess { process: child },
             Err(e) => {
                 log::error!("Error while spawning slirp: {e}");
-                return Err(Errcode::SlirpError(format!("Error while spawning slirp: {}", e)));
+                return Err(Errcode::IoError("Error while spawning slirp".to_string(), e));
             }
+        };
+
+        // End of file if slirp4netns exits before, it is killed on drop
+        let mut ready = [0u8; 1];
+        let mut ready_read = ready_read;
+        if let Err(e) = ready_read.read_exact(&mut ready) {
+            log::error!("slirp4netns did not configure the network: {e}");
+            return Err(Errcode::IoError("slirp4netns did not configure the network".to_string(), e));
         }
+        Ok(slirp)
     }
 }
 
 impl Drop for SlirpProcess {
     fn drop(&mut self) {
         let _ = self.process.kill();
+        let _ = self.process.wait();
     }
 }
-
-
@@ -0,0 +1,239 @@
+// Runtime state of the jails: each orjail parent writes the state of its jail
+// in $XDG_RUNTIME_DIR/orjail/<id>/state.json and keeps it locked while the
+// jail runs, a state file that is not locked belongs to a dead jail
+use crate::errors::Errcode;
+use crate::profile::Profile;
+use crate::seccomp::SeccompProfile;
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::getuid;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read_dir, read_link, read_to_string, remove_dir, remove_file, set_permissions, File, OpenOptions, Permissions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const STATE_FILE: &str = "state.json";
+pub const ATTACH_SOCKET: &str = "attach.sock";
+const TOR_LOG: &str = "notice.log";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JailState {
+    // The hostname of the jail
+    pub id: String,
+    pub command: String,
+    // Seconds since the epoch
+    pub started: u64,
+    // PID of the orjail parent and of the jail, as seen from the host
+    pub pid: i32,
+    pub child_pid: i32,
+    pub slirp_pid: Option<u32>,
+    pub namespace: String,
+    pub nftables_table: String,
+    pub root: Option<PathBuf>,
+    // Data directory of the TOR instance of the jail, as seen from the host
+    pub tor_data: PathBuf,
+    // Policy of the jail, applied as well to the commands run with exec
+    pub profile: Profile,
+    pub seccomp_profile: SeccompProfile,
+    pub disable_syscall: bool,
+    pub disable_capabilities: bool,
+    // cgroup of the jail when it has resource limits
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+}
+
+// The state directory of a running jail, removed with the lock when dropped.
+// The logs of detached jails are left for orjailrs logs, until orjailrs gc.
+pub struct StateDir {
+    dir: PathBuf,
+    _lock: File,
+}
+
+impl StateDir {
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for StateDir {
+    fn drop(&mut self) {
+        for name in [STATE_FILE, ATTACH_SOCKET] {
+            match remove_file(self.dir.join(name)) {
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => log::error!("Can not remove {}: {}", self.dir.join(name).display(), e),
+            }
+        }
+        match remove_dir(&self.dir) {
+            Ok(_) => {},
+            Err(e) if e.raw_os_error() == Some(libc::ENOTEMPTY) => {},
+            Err(e) => log::error!("Can not remove state directory {}: {}", self.dir.display(), e),
+        }
+    }
+}
+
+pub fn state_root() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("orjail"),
+        _ => std::env::temp_dir().join(format!("orjail-{}", getuid())),
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl JailState {
+    // The lock is taken before the state is written, so that a jail is never
+    // seen without it
+    pub fn save(&self) -> Result<StateDir, Errcode> {
+        let root = state_root();
+        let dir = root.join(&self.id);
+        if let Err(e) = create_dir_all(&dir) {
+            log::error!("Can not create state directory {}: {}", dir.display(), e);
+            return Err(Errcode::IoError("Can not create state directory".to_string(), e));
+        }
+        if let Err(e) = set_permissions(&root, Permissions::from_mode(0o700)) {
+            return Err(Errcode::IoError(format!("Can not restrict {}", root.display()), e));
+        }
+
+        let mut file = match OpenOptions::new().create(true).truncate(true).write(true).open(dir.join(STATE_FILE)) {
+            Ok(file) => file,
+            Err(e) => return Err(Errcode::IoError("Can not create state file".to_string(), e)),
+        };
+        if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            return Err(Errcode::SysError("Can not lock state file".to_string(), e));
+        }
+        let contents = match serde_json::to_string_pretty(self) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Errcode::StateError(format!("Can not serialize state: {e}"))),
+        };
+        if let Err(e) = file.write_all(contents.as_bytes()) {
+            return Err(Errcode::IoError("Can not write state file".to_string(), e));
+        }
+        log::debug!("Jail state written to {}", dir.display());
+        Ok(StateDir { dir, _lock: file })
+    }
+
+    pub fn load(dir: &Path) -> Result<(JailState, bool), Errcode> {
+        let path = dir.join(STATE_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(Errcode::IoError(format!("Can not open {}", path.display()), e)),
+        };
+        let alive = match flock(file.as_raw_fd(), FlockArg::LockSharedNonblock) {
+            Ok(_) => false,
+            Err(Errno::EWOULDBLOCK) => true,
+            Err(e) => return Err(Errcode::SysError(format!("Can not check lock of {}", path.display()), e)),
+        };
+        let contents = match read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Errcode::IoError(format!("Can not read {}", path.display()), e)),
+        };
+        match serde_json::from_str(&contents) {
+            Ok(state) => Ok((state, alive)),
+            Err(e) => Err(Errcode::StateError(format!("Invalid state file {}: {e}", path.display()))),
+        }
+    }
+
+    // TOR daemonizes in the jail, it is found by name among the processes
+    // of its PID namespace
+    pub fn tor_pid(&self) -> Option<i32> {
+        let jail_ns = read_link(format!("/proc/{}/ns/pid", self.child_pid)).ok()?;
+        read_dir("/proc").ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+            .find(|pid| {
+                read_link(format!("/proc/{}/ns/pid", pid)).ok().as_ref() == Some(&jail_ns)
+                    && read_to_string(format!("/proc/{}/comm", pid)).map(|comm| comm.trim() == "tor").unwrap_or(false)
+            })
+    }
+
+    // Last bootstrap progress in the TOR log, like "100%"
+    pub fn tor_bootstrap(&self) -> Option<String> {
+        let log = read_to_string(self.tor_data.join(TOR_LOG)).ok()?;
+        let line = log.lines().rev().find(|line| line.contains("Bootstrapped "))?;
+        let progress = line.split("Bootstrapped ").nth(1)?;
+        progress.split_whitespace().next().map(String::from)
+    }
+}
+
+// Every jail with a state directory, running or not
+pub fn list_jails() -> Result<Vec<(JailState, bool)>, Errcode> {
+    let entries = match read_dir(state_root()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(Errcode::IoError("Can not read state directory".to_string(), e)),
+    };
+
+    let mut jails = vec![];
+    for entry in entries.flatten() {
+        // Only the logs are left of detached jails that exited
+        if !entry.path().join(STATE_FILE).exists() {
+            continue;
+        }
+        match JailState::load(&entry.path()) {
+            Ok(jail) => jails.push(jail),
+            Err(e) => log::warn!("Skipping {}: {}", entry.path().display(), e),
+        }
+    }
+    jails.sort_by_key(|(state, _)| state.started);
+    Ok(jails)
+}
+
+// A running jail, by ID or by the PID of its orjail process or of its command
+pub fn find_jail(name: &str) -> Result<JailState, Errcode> {
+    let pid = name.parse::<i32>().ok();
+    let found = list_jails()?.into_iter()
+        .find(|(state, _)| state.id == name || Some(state.pid) == pid || Some(state.child_pid) == pid);
+    match found {
+        Some((state, true)) => Ok(state),
+        Some((state, false)) => Err(Errcode::StateError(format!("Jail {} is not running", state.id))),
+        None => Err(Errcode::StateError(format!("No jail named {}", name))),
+    }
+}
+
+// The state directory of a jail, running or not, and whether it runs. Detached
+// jails that exited are only found by ID.
+pub fn jail_dir(name: &str) -> Result<(PathBuf, bool), Errcode> {
+    let pid = name.parse::<i32>().ok();
+    let found = list_jails()?.into_iter()
+        .find(|(state, _)| state.id == name || Some(state.pid) == pid || Some(state.child_pid) == pid);
+    if let Some((state, alive)) = found {
+        return Ok((state_root().join(state.id), alive));
+    }
+    let dir = state_root().join(name);
+    if !name.contains('/') && dir.is_dir() {
+        Ok((dir, false))
+    } else {
+        Err(Errcode::StateError(format!("No jail named {}", name)))
+    }
+}
+
+pub fn list() -> Result<(), Errcode> {
+    println!("{:<14} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}  COMMAND", "ID", "STATUS", "UPTIME", "PID", "JAIL", "SLIRP", "TOR", "TOR BOOT");
+    for (state, alive) in list_jails()? {
+        let unknown = || "-".to_string();
+        let (status, tor, bootstrap) = if alive {
+            let tor = state.tor_pid().map(|pid| pid.to_string()).unwrap_or_else(unknown);
+            ("running", tor, state.tor_bootstrap().unwrap_or_else(unknown))
+        } else {
+            ("dead", unknown(), unknown())
+        };
+        let slirp = state.slirp_pid.map(|pid| pid.to_string()).unwrap_or_else(unknown);
+        println!("{:<14} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}  {}",
+            state.id, status, format_uptime(now().saturating_sub(state.started)), state.pid, state.child_pid, slirp, tor, bootstrap, state.command);
+    }
+    Ok(())
+}
+
+fn format_uptime(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
@@ -0,0 +1,549 @@
+// Seccomp user notification supervisor: the parent decides on selected
+// syscalls of the jail with their full context, and in audit mode records the
+// syscalls that the profile would refuse. Allowed syscalls continue in the
+// jail after the check, with arguments that may have changed meanwhile: the
+// supervisor is not a security boundary.
+use crate::audit::AuditRecord;
+use crate::errors::Errcode;
+use crate::ipc::recv_fd;
+
+use libseccomp::{notify_id_valid, ScmpNotifReq, ScmpNotifResp, ScmpNotifRespFlags};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_link, File};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Component, PathBuf};
+use std::thread::{spawn, JoinHandle};
+
+const PAGE_SIZE: u64 = 4096;
+const PATH_MAX: usize = 4096;
+// Larger than any sockaddr we look into
+const SOCKADDR_MAX: u64 = 128;
+// Layout of struct msghdr and struct mmsghdr on 64 bit architectures
+const MSGHDR_NAME_SIZE: usize = 12;
+const MMSGHDR_SIZE: u64 = 64;
+// The kernel sends at most UIO_MAXIOV messages in one sendmmsg
+const SENDMMSG_MAX: u64 = 1024;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorPolicy {
+    pub connect: ConnectPolicy,
+    pub paths: PathPolicy,
+}
+
+// Destinations that connect() may reach, as "address[/prefix][:port]".
+// By default the TOR TransPort and DNSPort, the addresses that TOR maps
+// hostnames to, and DNS servers, whose traffic is redirected to TOR.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectPolicy {
+    pub enabled: bool,
+    pub allowed: Vec<String>,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        ConnectPolicy {
+            enabled: false,
+            allowed: vec![
+                "10.40.50.10:9050".to_string(),
+                "10.40.50.10:5353".to_string(),
+                "10.40.0.0/16".to_string(),
+                "0.0.0.0/0:53".to_string(),
+            ],
+        }
+    }
+}
+
+// Paths, and everything below them, that the jail can not open or mount
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathPolicy {
+    pub enabled: bool,
+    pub denied: Vec<PathBuf>,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        let denied = [
+            "/proc/kcore",
+            "/proc/kmsg",
+            "/proc/sysrq-trigger",
+            "/sys/firmware",
+            "/sys/kernel",
+            "/dev/mem",
+            "/dev/kmem",
+            "/dev/port",
+            "/run/netns",
+        ];
+        PathPolicy {
+            enabled: false,
+            denied: denied.iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+impl SupervisorPolicy {
+    pub fn enabled(&self) -> bool {
+        self.connect.enabled || self.paths.enabled
+    }
+
+    // Syscalls notified to the supervisor
+    pub fn syscalls(&self) -> Vec<&'static str> {
+        let mut syscalls = vec![];
+        // Sends with a destination address reach it without connect, e.g.
+        // UDP or TCP Fast Open
+        if self.connect.enabled {
+            syscalls.extend(["connect", "sendto", "sendmsg", "sendmmsg"]);
+        }
+        if self.paths.enabled {
+            syscalls.extend(["open", "openat", "openat2", "mount"]);
+        }
+        syscalls
+    }
+
+    pub fn supervises(&self, name: &str) -> bool {
+        self.syscalls().contains(&name)
+    }
+
+    pub fn validate(&self) -> Result<(), Errcode> {
+        self.connect.rules()?;
+        Ok(())
+    }
+}
+
+impl ConnectPolicy {
+    fn rules(&self) -> Result<Vec<AddressRule>, Errcode> {
+        self.allowed.iter().map(|rule| AddressRule::parse(rule)).collect()
+    }
+}
+
+struct AddressRule {
+    network: IpAddr,
+    prefix: u8,
+    port: Option<u16>,
+}
+
+impl AddressRule {
+    fn parse(rule: &str) -> Result<AddressRule, Errcode> {
+        let invalid = |reason: &str| Errcode::ProfileError(format!("Invalid connect rule {}: {}", rule, reason));
+
+        // IPv6 addresses with a port are written as [address]:port
+        let (address, port) = if let Some(rest) = rule.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((address, "")) => (address, None),
+                Some((address, port)) => match port.strip_prefix(':') {
+                    Some(port) => (address, Some(port)),
+                    None => return Err(invalid("expected :port after ]")),
+                },
+                None => return Err(invalid("missing ]")),
+            }
+        } else {
+            match rule.rsplit_once(':') {
+                Some((address, port)) if !address.contains(':') => (address, Some(port)),
+                _ => (rule, None),
+            }
+        };
+
+        let port = match port {
+            Some(port) => Some(port.parse::<u16>().map_err(|e| invalid(&e.to_string()))?),
+            None => None,
+        };
+        let (network, prefix) = match address.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix.parse::<u8>().map_err(|e| invalid(&e.to_string()))?)),
+            None => (address, None),
+        };
+        let network: IpAddr = network.parse().map_err(|e: std::net::AddrParseError| invalid(&e.to_string()))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return Err(invalid("prefix too long"));
+        }
+
+        Ok(AddressRule { network, prefix, port })
+    }
+
+    fn matches(&self, address: &IpAddr, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Wait for the child to send the notification fd and serve it until every
+// process of the jail has exited. The audited syscalls are returned.
+pub fn start_supervisor(socket: RawFd, policy: SupervisorPolicy, audit: bool) -> JoinHandle<Vec<AuditRecord>> {
+    spawn(move || {
+        match recv_fd(socket) {
+            Ok(notify_fd) => supervise(notify_fd, &policy, audit),
+            Err(e) => {
+                log::error!("Seccomp supervisor disabled, can not receive the notification fd: {}", e);
+                vec![]
+            }
+        }
+    })
+}
+
+fn supervise(notify_fd: OwnedFd, policy: &SupervisorPolicy, audit: bool) -> Vec<AuditRecord> {
+    // Already validated with the profile
+    let rules = policy.connect.rules().unwrap_or_default();
+    let mut records = vec![];
+    loop {
+        let mut fds = [PollFd::new(&notify_fd, PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Ok(_) => {},
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                log::error!("Can not poll seccomp notification fd: {}", e);
+                break;
+            }
+        }
+        // No process is using the filter anymore
+        if fds[0].revents().is_some_and(|events| events.contains(PollFlags::POLLHUP)) {
+            break;
+        }
+
+        let req = match ScmpNotifReq::receive(notify_fd.as_raw_fd()) {
+            Ok(req) => req,
+            Err(e) => {
+                // The process may have been killed in the meantime
+                log::debug!("Can not receive seccomp notification: {}", e);
+                continue;
+            }
+        };
+        let syscall = match req.data.syscall.get_name() {
+            Ok(name) => name,
+            Err(_) => format!("{}", i32::from(req.data.syscall)),
+        };
+
+        let resp = if policy.supervises(&syscall) {
+            match mediate(notify_fd.as_raw_fd(), &req, &syscall, policy, &rules) {
+                Ok(()) => ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::CONTINUE),
+                Err((errno, reason)) => {
+                    log::warn!("Supervisor refused {} to process {}: {}", syscall, req.pid, reason);
+                    ScmpNotifResp::new_error(req.id, -(errno as i32), ScmpNotifRespFlags::empty())
+                }
+            }
+        } else {
+            if audit {
+                log::info!("Seccomp audit: process {} would be refused {}({:#x?})", req.pid, syscall, req.data.args);
+                records.push(AuditRecord {
+                    syscall,
+                    args: req.data.args,
+                });
+            }
+            ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::CONTINUE)
+        };
+
+        if let Err(e) = resp.respond(notify_fd.as_raw_fd()) {
+            log::debug!("Can not answer seccomp notification: {}", e);
+        }
+    }
+    records
+}
+
+// The arguments are read from the memory of the process. Another thread of
+// the process could change them after the check, so this is an additional
+// layer on top of the network namespace and the mounts, not a replacement.
+fn mediate(notify_fd: RawFd, req: &ScmpNotifReq, syscall: &str, policy: &SupervisorPolicy, rules: &[AddressRule]) -> Result<(), (Errno, String)> {
+    let args = req.data.args;
+    let paths = match syscall {
+        "connect" | "sendto" | "sendmsg" | "sendmmsg" => return mediate_network(req.pid, syscall, &args, rules),
+        "open" => vec![resolve_path(req.pid, libc::AT_FDCWD, args[0])?],
+        "openat" | "openat2" => vec![resolve_path(req.pid, args[0] as i32, args[1])?],
+        "mount" => {
+            let mut paths = vec![resolve_path(req.pid, libc::AT_FDCWD, args[1])?];
+            // The source is a path for bind mounts only, but checking it
+            // does not hurt for the others
+            if args[0] != 0 {
+                paths.push(resolve_path(req.pid, libc::AT_FDCWD, args[0])?);
+            }
+            paths
+        }
+        _ => return Ok(()),
+    };
+
+    // The process could have been replaced while we were reading its memory
+    if notify_id_valid(notify_fd, req.id).is_err() {
+        return Err((Errno::EACCES, "process exited during the check".to_string()));
+    }
+
+    for path in paths.iter() {
+        if let Some(denied) = policy.paths.denied.iter().find(|denied| path.starts_with(denied)) {
+            return Err((Errno::EACCES, format!("{} is below the denied path {}", path.display(), denied.display())));
+        }
+    }
+    Ok(())
+}
+
+// Destinations given to sendto, sendmsg and sendmmsg go through the rules of
+// connect, sends on connected sockets need no address
+fn mediate_network(pid: u32, syscall: &str, args: &[u64; 6], rules: &[AddressRule]) -> Result<(), (Errno, String)> {
+    match syscall {
+        "connect" => mediate_connect(pid, args[1], args[2], rules),
+        "sendto" if args[4] != 0 => mediate_connect(pid, args[4], args[5], rules),
+        "sendmsg" => mediate_msghdr(pid, args[1], rules),
+        "sendmmsg" => {
+            for i in 0..args[2].min(SENDMMSG_MAX) {
+                mediate_msghdr(pid, args[1].wrapping_add(i * MMSGHDR_SIZE), rules)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn mediate_msghdr(pid: u32, addr: u64, rules: &[AddressRule]) -> Result<(), (Errno, String)> {
+    let msghdr = read_memory(pid, addr, MSGHDR_NAME_SIZE)?;
+    let mut name = [0; 8];
+    name.copy_from_slice(&msghdr[..8]);
+    let len = u32::from_ne_bytes([msghdr[8], msghdr[9], msghdr[10], msghdr[11]]);
+    match u64::from_ne_bytes(name) {
+        0 => Ok(()),
+        name => mediate_connect(pid, name, len as u64, rules),
+    }
+}
+
+fn mediate_connect(pid: u32, addr: u64, len: u64, rules: &[AddressRule]) -> Result<(), (Errno, String)> {
+    // The kernel refuses a too short address on its own
+    if len < 2 {
+        return Ok(());
+    }
+    let sockaddr = read_memory(pid, addr, len.min(SOCKADDR_MAX) as usize)?;
+    let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) as i32;
+    let port = |data: &[u8]| u16::from_be_bytes([data[2], data[3]]);
+
+    let (address, port) = match family {
+        // AF_UNSPEC dissolves the association of a datagram socket
+        libc::AF_UNIX | libc::AF_UNSPEC => return Ok(()),
+        libc::AF_INET if sockaddr.len() >= 8 => {
+            let octets: [u8; 4] = [sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7]];
+            (IpAddr::V4(Ipv4Addr::from(octets)), port(&sockaddr))
+        }
+        libc::AF_INET6 if sockaddr.len() >= 24 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&sockaddr[8..24]);
+            let address = Ipv6Addr::from(octets);
+            match address.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), port(&sockaddr)),
+                None => (IpAddr::V6(address), port(&sockaddr)),
+            }
+        }
+        _ => return Err((Errno::EPERM, format!("address family {} is not allowed", family))),
+    };
+
+    if rules.iter().any(|rule| rule.matches(&address, port)) {
+        Ok(())
+    } else {
+        Err((Errno::EPERM, format!("{} port {} is not an allowed destination", address, port)))
+    }
+}
+
+fn read_memory(pid: u32, addr: u64, len: usize) -> Result<Vec<u8>, (Errno, String)> {
+    let mem = File::open(format!("/proc/{}/mem", pid))
+        .map_err(|e| (Errno::EACCES, format!("can not open memory of process {}: {}", pid, e)))?;
+    let mut data = vec![0; len];
+    mem.read_exact_at(&mut data, addr)
+        .map_err(|e| (Errno::EFAULT, format!("can not read memory of process {}: {}", pid, e)))?;
+    Ok(data)
+}
+
+// Read the NUL terminated path at addr, page by page so that no unmapped
+// memory after the string is touched
+fn read_string(pid: u32, addr: u64) -> Result<Vec<u8>, (Errno, String)> {
+    let mut path = vec![];
+    let mut offset = addr;
+    while path.len() < PATH_MAX {
+        let chunk = (PAGE_SIZE - offset % PAGE_SIZE) as usize;
+        let data = read_memory(pid, offset, chunk)?;
+        if let Some(end) = data.iter().position(|b| *b == 0) {
+            path.extend_from_slice(&data[..end]);
+            return Ok(path);
+        }
+        path.extend_from_slice(&data);
+        offset += chunk as u64;
+    }
+    Err((Errno::ENAMETOOLONG, format!("path of process {} is too long", pid)))
+}
+
+// Absolute and lexically normalized path, as seen by the process. Symbolic
+// links are not followed.
+fn resolve_path(pid: u32, dirfd: i32, addr: u64) -> Result<PathBuf, (Errno, String)> {
+    let raw = read_string(pid, addr)?;
+    let path = PathBuf::from(String::from_utf8_lossy(&raw).into_owned());
+
+    let full = if path.is_absolute() {
+        path
+    } else {
+        let base = if dirfd == libc::AT_FDCWD {
+            format!("/proc/{}/cwd", pid)
+        } else {
+            format!("/proc/{}/fd/{}", pid, dirfd)
+        };
+        match read_link(&base) {
+            Ok(base) => base.join(path),
+            Err(e) => return Err((Errno::EACCES, format!("can not resolve {}: {}", base, e))),
+        }
+    };
+
+    let mut normalized = PathBuf::from("/");
+    for component in full.components() {
+        match component {
+            Component::ParentDir => { normalized.pop(); },
+            Component::Normal(name) => normalized.push(name),
+            _ => {},
+        }
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn sockaddr_in(address: [u8; 4], port: u16) -> libc::sockaddr_in {
+        libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: port.to_be(),
+            sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(address) },
+            sin_zero: [0; 8],
+        }
+    }
+
+    fn msghdr(name: Option<&libc::sockaddr_in>) -> libc::msghdr {
+        let mut msghdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        if let Some(name) = name {
+            msghdr.msg_name = name as *const _ as *mut libc::c_void;
+            msghdr.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as u32;
+        }
+        msghdr
+    }
+
+    // The arguments are read from the memory of the test itself
+    fn mediate_send(syscall: &str, args: [u64; 6]) -> Result<(), (Errno, String)> {
+        let rules = vec![AddressRule::parse("10.40.50.10:9050").unwrap()];
+        mediate_network(std::process::id(), syscall, &args, &rules)
+    }
+
+    #[test]
+    fn address_rule_with_prefix_and_port() {
+        let rule = AddressRule::parse("10.1.2.3/8:9050").unwrap();
+        assert!(rule.matches(&ip("10.200.0.1"), 9050));
+        assert!(!rule.matches(&ip("10.200.0.1"), 9051));
+        assert!(!rule.matches(&ip("11.0.0.1"), 9050));
+    }
+
+    #[test]
+    fn address_rule_without_prefix_is_a_single_address() {
+        let rule = AddressRule::parse("127.0.0.1").unwrap();
+        assert!(rule.matches(&ip("127.0.0.1"), 53));
+        assert!(!rule.matches(&ip("127.0.0.2"), 53));
+    }
+
+    #[test]
+    fn address_rule_zero_prefix_matches_its_family_only() {
+        let rule = AddressRule::parse("0.0.0.0/0").unwrap();
+        assert!(rule.matches(&ip("0.0.0.0"), 1));
+        assert!(rule.matches(&ip("255.255.255.255"), 1));
+        assert!(!rule.matches(&ip("::1"), 1));
+
+        let rule = AddressRule::parse("::/0").unwrap();
+        assert!(rule.matches(&ip("2001:db8::1"), 443));
+        assert!(!rule.matches(&ip("10.0.0.1"), 443));
+    }
+
+    #[test]
+    fn address_rule_ipv6() {
+        let rule = AddressRule::parse("[2001:db8::/32]:443").unwrap();
+        assert!(rule.matches(&ip("2001:db8:ffff::1"), 443));
+        assert!(!rule.matches(&ip("2001:db9::1"), 443));
+        assert!(!rule.matches(&ip("2001:db8::1"), 80));
+
+        let rule = AddressRule::parse("::1").unwrap();
+        assert_eq!(rule.port, None);
+        assert!(rule.matches(&ip("::1"), 80));
+        assert!(!rule.matches(&ip("::2"), 80));
+
+        let rule = AddressRule::parse("[::1]").unwrap();
+        assert!(rule.matches(&ip("::1"), 80));
+    }
+
+    // IPv4-mapped destinations are converted to IPv4 before they are matched
+    #[test]
+    fn address_rule_ipv4_does_not_match_mapped_ipv6() {
+        let rule = AddressRule::parse("10.0.0.1").unwrap();
+        assert!(!rule.matches(&ip("::ffff:10.0.0.1"), 80));
+    }
+
+    #[test]
+    fn address_rule_invalid() {
+        for rule in ["10.0.0.1/33", "::1/129", "[::1", "[::1]80", "10.0.0.1:65536", "10.0.0.1:", "10.0.0.1/", "host:80", ""] {
+            assert!(AddressRule::parse(rule).is_err(), "{rule}");
+        }
+        assert!(AddressRule::parse("::1/128").is_ok());
+    }
+
+    #[test]
+    fn sends_with_an_address_are_supervised() {
+        let policy = SupervisorPolicy { connect: ConnectPolicy { enabled: true, ..Default::default() }, ..Default::default() };
+        for syscall in ["connect", "sendto", "sendmsg", "sendmmsg"] {
+            assert!(policy.supervises(syscall), "{syscall}");
+        }
+        assert!(!SupervisorPolicy::default().supervises("sendto"));
+    }
+
+    #[test]
+    fn sendto_with_address_follows_connect_rules() {
+        let (allowed, refused) = (sockaddr_in([10, 40, 50, 10], 9050), sockaddr_in([1, 1, 1, 1], 53));
+        let len = std::mem::size_of::<libc::sockaddr_in>() as u64;
+        assert!(mediate_send("sendto", [3, 0, 0, 0, &allowed as *const _ as u64, len]).is_ok());
+        assert_eq!(mediate_send("sendto", [3, 0, 0, 0, &refused as *const _ as u64, len]).unwrap_err().0, Errno::EPERM);
+        // Connected sockets send without an address
+        assert!(mediate_send("sendto", [3, 0, 0, 0, 0, 0]).is_ok());
+    }
+
+    // TCP Fast Open connects from sendto, without calling connect
+    #[test]
+    fn sendto_fast_open_follows_connect_rules() {
+        let refused = sockaddr_in([93, 184, 216, 34], 443);
+        let len = std::mem::size_of::<libc::sockaddr_in>() as u64;
+        let flags = libc::MSG_FASTOPEN as u64;
+        assert!(mediate_send("sendto", [3, 0, 0, flags, &refused as *const _ as u64, len]).is_err());
+    }
+
+    #[test]
+    fn sendmsg_and_sendmmsg_follow_connect_rules() {
+        let (allowed, refused) = (sockaddr_in([10, 40, 50, 10], 9050), sockaddr_in([1, 1, 1, 1], 53));
+        for (name, ok) in [(None, true), (Some(&allowed), true), (Some(&refused), false)] {
+            let msghdr = msghdr(name);
+            assert_eq!(mediate_send("sendmsg", [3, &msghdr as *const _ as u64, 0, 0, 0, 0]).is_ok(), ok);
+        }
+
+        let messages = [
+            libc::mmsghdr { msg_hdr: msghdr(Some(&allowed)), msg_len: 0 },
+            libc::mmsghdr { msg_hdr: msghdr(Some(&refused)), msg_len: 0 },
+        ];
+        assert_eq!(std::mem::size_of::<libc::mmsghdr>() as u64, MMSGHDR_SIZE);
+        assert!(mediate_send("sendmmsg", [3, messages.as_ptr() as u64, 1, 0, 0, 0]).is_ok());
+        assert!(mediate_send("sendmmsg", [3, messages.as_ptr() as u64, 2, 0, 0, 0]).is_err());
+    }
+}
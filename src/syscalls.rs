@@ -1,73 +1,442 @@
-use syscallz::{Context, Action, Syscall, Comparator, Cmp};
-use libc::TIOCSTI;
-use nix::sys::stat::Mode;
-use nix::sched::CloneFlags;
+use libseccomp::{ScmpAction, ScmpArgCompare, ScmpCompareOp, ScmpFilterContext, ScmpSyscall};
+use serde::{Deserialize, Serialize};
+use std::os::unix::io::RawFd;
 
 use crate::errors::Errcode;
+use crate::profile::SeccompPolicy;
+use crate::seccomp::SeccompProfile;
+use crate::supervisor::SupervisorPolicy;
 
-pub fn setsyscalls() -> Result<(), Errcode> {
+// Only the lower bits of the socket type select the type, the remaining
+// ones are flags such as SOCK_CLOEXEC and SOCK_NONBLOCK
+const SOCK_TYPE_MASK: u64 = 0xf;
+// Socket families and levels are plain C int
+const INT_MASK: u64 = u32::MAX as u64;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketFamily {
+    Unix,
+    Inet,
+    Inet6,
+    Netlink,
+    Packet,
+    Alg,
+    Bluetooth,
+    Can,
+    Vsock,
+    Xdp,
+}
+
+impl SocketFamily {
+    fn raw(&self) -> u64 {
+        let family = match self {
+            SocketFamily::Unix => libc::AF_UNIX,
+            SocketFamily::Inet => libc::AF_INET,
+            SocketFamily::Inet6 => libc::AF_INET6,
+            SocketFamily::Netlink => libc::AF_NETLINK,
+            SocketFamily::Packet => libc::AF_PACKET,
+            SocketFamily::Alg => libc::AF_ALG,
+            SocketFamily::Bluetooth => libc::AF_BLUETOOTH,
+            SocketFamily::Can => libc::AF_CAN,
+            SocketFamily::Vsock => libc::AF_VSOCK,
+            SocketFamily::Xdp => libc::AF_XDP,
+        };
+        family as u64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketType {
+    Stream,
+    Dgram,
+    Seqpacket,
+    Raw,
+}
+
+impl SocketType {
+    fn raw(&self) -> u64 {
+        let sock_type = match self {
+            SocketType::Stream => libc::SOCK_STREAM,
+            SocketType::Dgram => libc::SOCK_DGRAM,
+            SocketType::Seqpacket => libc::SOCK_SEQPACKET,
+            SocketType::Raw => libc::SOCK_RAW,
+        };
+        sock_type as u64
+    }
+}
+
+// Network hardening group: restricts the sockets that the jail can open,
+// so that it can not probe or bypass the redirection of its traffic to TOR
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkFilter {
+    pub enabled: bool,
+    pub families: Vec<SocketFamily>,
+    pub types: Vec<SocketType>,
+    // Deny IP_TRANSPARENT and IPV6_TRANSPARENT socket options
+    pub deny_transparent: bool,
+}
+
+impl NetworkFilter {
+    // Syscalls whose allow rules are generated by the network filter in
+    // allowlist mode, replacing the ones of the seccomp profile. The filter
+    // only narrows what the profile permits, so the profile has to allow them
+    // whatever their arguments, otherwise its own rules are kept.
+    fn owns(&self, name: &str, profile: &SeccompProfile) -> bool {
+        (name == "socket" || (self.deny_transparent && name == "setsockopt")) && profile.allows(name)
+    }
+}
+
+impl Default for NetworkFilter {
+    fn default() -> Self {
+        NetworkFilter {
+            enabled: true,
+            families: vec![SocketFamily::Unix, SocketFamily::Inet, SocketFamily::Inet6],
+            types: vec![SocketType::Stream, SocketType::Dgram],
+            deny_transparent: true,
+        }
+    }
+}
+
+// In audit mode or when the supervisor is enabled, the notification fd is
+// returned once the filter is loaded, to be handed over to the parent on socket
+pub fn setsyscalls(policy: &SeccompPolicy, profile: &SeccompProfile, supervisor: &SupervisorPolicy, audit: bool, socket: RawFd) -> Result<Option<RawFd>, Errcode> {
     log::debug!("Refusing / Filtering unwanted syscalls");
-    // Unconditional syscall deny
-    let syscalls_refused = [
-        Syscall::keyctl,
-        Syscall::add_key,
-        Syscall::request_key,
-        Syscall::mbind,
-        Syscall::migrate_pages,
-        Syscall::move_pages,
-        Syscall::set_mempolicy,
-        Syscall::userfaultfd,
-        Syscall::perf_event_open,
-    ];
-    // Contional syscall deny
-    let s_isuid: u64 = Mode::S_ISUID.bits().into();
-    let s_isgid: u64 = Mode::S_ISGID.bits().into();
-    let clone_new_user: u64 = CloneFlags::CLONE_NEWUSER.bits() as u64;
-    let syscalls_refuse_ifcomp = [
-        (Syscall::chmod, 1, s_isuid),
-        (Syscall::chmod, 1, s_isgid),
-        (Syscall::fchmod, 1, s_isuid),
-        (Syscall::fchmod, 1, s_isgid),
-        (Syscall::fchmodat, 2, s_isuid),
-        (Syscall::fchmodat, 2, s_isgid),
-        (Syscall::unshare, 0, clone_new_user),
-        (Syscall::clone, 0, clone_new_user),
-        (Syscall::ioctl, 1, TIOCSTI),
+    let notify = audit || supervisor.enabled();
+    let default_action = audited(profile.default_action()?, audit);
+    // Profiles that allow every syscall by default are denylists, everything
+    // else is an allowlist where each permitted syscall is listed
+    let allowlist = !matches!(default_action, ScmpAction::Allow | ScmpAction::Log);
+    let network = if policy.network.enabled { Some(&policy.network) } else { None };
+
+    let mut ctx = match ScmpFilterContext::new_filter(default_action) {
+        Ok(ctx) => ctx,
+        Err(e) => return Err(Errcode::SyscallsError(format!("Error during syscall filter context init: {e}"))),
+    };
+    for arch in profile.arches()? {
+        if let Err(e) = ctx.add_arch(arch) {
+            return Err(Errcode::SyscallsError(format!("Can not add architecture {:?} to syscall filter: {e}", arch)));
+        }
+    }
+
+    for rule in profile.syscalls.iter() {
+        let rule_action = audited(rule.action()?, audit);
+        for (name, sc) in rule.syscalls() {
+            // The network group takes care of these syscalls on its own
+            if allowlist && network.is_some_and(|filter| filter.owns(name, profile)) {
+                log::debug!("Syscall {} is handled by the network filter", name);
+                continue;
+            }
+            // The supervisor decides on what the profile allows, what it
+            // refuses stays refused
+            let supervised = matches!(rule_action, ScmpAction::Allow | ScmpAction::Log) && supervisor.supervises(name);
+            for comps in rule.comparators()? {
+                let rules = if supervised {
+                    supervised_rules(name, rule_action, comps, socket)
+                } else {
+                    vec![(rule_action, comps)]
+                };
+                for (action, comps) in rules {
+                    // libseccomp refuses rules that match the default action
+                    if action != default_action {
+                        add_rule(&mut ctx, action, sc, &comps)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(filter) = network {
+        filter_network(&mut ctx, filter, profile, allowlist, audit)?;
+    }
+
+    // Supervised syscalls that a denylist allows without a rule of their
+    // own. A notification for the whole syscall would take over the
+    // refusals of the profile for some of its arguments, so the syscalls
+    // with rules are only notified where those rules allow them.
+    for name in supervisor.syscalls() {
+        if allowlist || profile.has_rule(name) {
+            continue;
+        }
+        let sc = match ScmpSyscall::from_name(name) {
+            Ok(sc) => sc,
+            Err(_) => {
+                log::debug!("Skipping unknown supervised syscall {}", name);
+                continue;
+            }
+        };
+        for (action, comps) in supervised_rules(name, default_action, vec![], socket) {
+            if action != default_action {
+                add_rule(&mut ctx, action, sc, &comps)?;
+            }
+        }
+    }
+
+    // The end of the setup and the notification fd are sent once the filter
+    // is loaded. Until the parent holds the fd nobody can answer, so sending
+    // it must not be notified either. A default Allow already lets it through.
+    if (notify || allowlist) && default_action != ScmpAction::Allow {
+        let sendmsg = ScmpSyscall::from(libc::SYS_sendmsg as i32);
+        add_rule(&mut ctx, ScmpAction::Allow, sendmsg, &[ScmpArgCompare::new(0, ScmpCompareOp::Equal, socket as u64)])?;
+    }
+
+    if let Err(e) = ctx.load() {
+        return Err(Errcode::SyscallsError(format!("Error during syscall filter context load: {e}")));
+    }
+
+    if !notify {
+        return Ok(None);
+    }
+    match ctx.get_notify_fd() {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) => Err(Errcode::SyscallsError(format!("Can not get seccomp notification fd: {e}"))),
+    }
+}
+
+// Rules of a syscall that the profile allows with action and the supervisor
+// decides on. Sends without a destination address, and the messages of the
+// setup on socket, are left to action. libseccomp accepts a single comparison
+// for each argument, so rules with comparators of their own are all notified.
+fn supervised_rules(name: &str, action: ScmpAction, comps: Vec<ScmpArgCompare>, socket: RawFd) -> Vec<(ScmpAction, Vec<ScmpArgCompare>)> {
+    if !comps.is_empty() {
+        return vec![(ScmpAction::Notify, comps)];
+    }
+    match name {
+        "sendto" => vec![
+            (ScmpAction::Notify, vec![ScmpArgCompare::new(4, ScmpCompareOp::NotEqual, 0)]),
+            (action, vec![ScmpArgCompare::new(4, ScmpCompareOp::Equal, 0)]),
+        ],
+        // The rule allowing socket is added with the filter
+        "sendmsg" => vec![(ScmpAction::Notify, vec![ScmpArgCompare::new(0, ScmpCompareOp::NotEqual, socket as u64)])],
+        _ => vec![(ScmpAction::Notify, comps)],
+    }
+}
+
+// In audit mode every syscall that would be refused is notified to the parent,
+// that logs it and lets it continue
+fn audited(action: ScmpAction, audit: bool) -> ScmpAction {
+    match action {
+        ScmpAction::Allow | ScmpAction::Log => action,
+        _ if audit => ScmpAction::Notify,
+        _ => action,
+    }
+}
+
+// In allowlist mode only the permitted sockets are allowed, otherwise all the
+// others are refused
+fn filter_network(ctx: &mut ScmpFilterContext, filter: &NetworkFilter, profile: &SeccompProfile, allowlist: bool, audit: bool) -> Result<(), Errcode> {
+    log::debug!("Restricting sockets to families {:?} and types {:?}", filter.families, filter.types);
+    let families: Vec<u64> = filter.families.iter().map(|f| f.raw()).collect();
+    let types: Vec<u64> = filter.types.iter().map(|t| t.raw()).collect();
+    let transparent_opts = [
+        (libc::SOL_IP as u64, libc::IP_TRANSPARENT as u64),
+        (libc::SOL_IPV6 as u64, libc::IPV6_TRANSPARENT as u64),
     ];
-    // Initialize a profile with all syscalls allowed by default
-    //
-    if let Ok(mut ctx) = Context::init_with_action(Action::Allow) {
-        // Configure our profile
-        for sc in syscalls_refused.iter() {
-            refuse_syscall(&mut ctx, sc)?;
+
+    if allowlist {
+        // Otherwise the rules of the profile apply as they are
+        if filter.owns("socket", profile) {
+            for family in families.iter() {
+                for sock_type in types.iter() {
+                    add_rule(ctx, ScmpAction::Allow, socket(), &[
+                        ScmpArgCompare::new(0, ScmpCompareOp::MaskedEqual(INT_MASK), *family),
+                        ScmpArgCompare::new(1, ScmpCompareOp::MaskedEqual(SOCK_TYPE_MASK), *sock_type),
+                    ])?;
+                }
+            }
         }
 
-        for (sc, ind, biteq) in syscalls_refuse_ifcomp.iter() {
-            refuse_if_comp(&mut ctx, *ind, sc, *biteq)?;
+        if filter.owns("setsockopt", profile) {
+            let levels: Vec<u64> = transparent_opts.iter().map(|(level, _)| *level).collect();
+            for comp in complement_comparators(1, &levels, INT_MASK) {
+                add_rule(ctx, ScmpAction::Allow, setsockopt(), &[comp])?;
+            }
+            for (level, optname) in transparent_opts.iter() {
+                for comp in complement_comparators(2, &[*optname], INT_MASK) {
+                    add_rule(ctx, ScmpAction::Allow, setsockopt(), &[
+                        ScmpArgCompare::new(1, ScmpCompareOp::MaskedEqual(INT_MASK), *level),
+                        comp,
+                    ])?;
+                }
+            }
         }
+        return Ok(());
+    }
+
+    // Refuse socket() for every family and type that is not explicitly allowed,
+    // EAFNOSUPPORT lets programs fall back to the families that are available
+    for comp in complement_comparators(0, &families, INT_MASK) {
+        add_rule(ctx, audited(ScmpAction::Errno(libc::EAFNOSUPPORT), audit), socket(), &[comp])?;
+    }
+    for comp in complement_comparators(1, &types, SOCK_TYPE_MASK) {
+        add_rule(ctx, audited(ScmpAction::Errno(libc::EPERM), audit), socket(), &[comp])?;
+    }
 
-        if let Err(e) = ctx.load() {
-            return Err(Errcode::SyscallsError(format!("Error during syscall filter context load: {e}")));
+    if filter.deny_transparent {
+        for (level, optname) in transparent_opts.iter() {
+            add_rule(ctx, audited(ScmpAction::Errno(libc::EPERM), audit), setsockopt(), &[
+                ScmpArgCompare::new(1, ScmpCompareOp::MaskedEqual(INT_MASK), *level),
+                ScmpArgCompare::new(2, ScmpCompareOp::MaskedEqual(INT_MASK), *optname),
+            ])?;
         }
+    }
+    Ok(())
+}
+
+fn socket() -> ScmpSyscall {
+    ScmpSyscall::from(libc::SYS_socket as i32)
+}
 
-        Ok(())
+fn setsockopt() -> ScmpSyscall {
+    ScmpSyscall::from(libc::SYS_setsockopt as i32)
+}
+
+fn add_rule(ctx: &mut ScmpFilterContext, action: ScmpAction, sc: ScmpSyscall, comps: &[ScmpArgCompare]) -> Result<(), Errcode> {
+    let res = if comps.is_empty() {
+        ctx.add_rule(action, sc)
     } else {
-        Err(Errcode::SyscallsError("Error during syscall filter context init".to_string()))
+        ctx.add_rule_conditional(action, sc, comps)
+    };
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Errcode::SyscallsError(format!("Syscall rule returned error {} for syscall {}", e, i32::from(sc)))),
     }
 }
 
-const EPERM: u16 = 1;
-fn refuse_syscall(ctx: &mut Context, sc: &Syscall) -> Result<(), Errcode>{
-    match ctx.set_action_for_syscall(Action::Errno(EPERM), *sc){
-        Ok(_) => Ok(()),
-        Err(e) => Err(Errcode::SyscallsError(format!("Set action EPERM for syscall {} returned error {}", sc.into_i32(), e))),
+// libseccomp accepts a single comparison for each argument of a rule, so
+// "argument not in values" is split in one comparator for each range of values
+// in between. Ranges are matched by power of two aligned blocks with MaskedEq,
+// which only look at the bits of mask: the kernel truncates int arguments to
+// their lower 32 bits, a comparison on the whole 64 bits could be passed by
+// setting the upper ones.
+fn complement_comparators(arg: u32, values: &[u64], mask: u64) -> Vec<ScmpArgCompare> {
+    complement_blocks(values, mask)
+        .into_iter()
+        .map(|(block_mask, value)| ScmpArgCompare::new(arg, ScmpCompareOp::MaskedEqual(block_mask), value))
+        .collect()
+}
+
+// The (mask, value) pairs of the blocks covering every masked value that is
+// not in values
+fn complement_blocks(values: &[u64], mask: u64) -> Vec<(u64, u64)> {
+    let mut sorted: Vec<u64> = values.iter().map(|v| v & mask).collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut blocks = vec![];
+    let mut low = 0;
+    for value in sorted.iter() {
+        if *value > low {
+            masked_blocks(low, *value - 1, mask, &mut blocks);
+        }
+        low = *value + 1;
+    }
+    match sorted.last() {
+        Some(last) if *last < mask => masked_blocks(low, mask, mask, &mut blocks),
+        Some(_) => {},
+        None => masked_blocks(0, mask, mask, &mut blocks),
     }
+    blocks
 }
 
-fn refuse_if_comp(ctx: &mut Context, ind: u32, sc: &Syscall, biteq: u64)-> Result<(), Errcode>{
-    match ctx.set_rule_for_syscall(Action::Errno(EPERM), *sc,
-            &[Comparator::new(ind, Cmp::MaskedEq, biteq, Some(biteq))]){
-        Ok(_) => Ok(()),
-        Err(e) => Err(Errcode::SyscallsError(format!("Syscall comparator returned error {} for syscall {}", e, sc.into_i32()))),
+fn masked_blocks(low: u64, high: u64, mask: u64, blocks: &mut Vec<(u64, u64)>) {
+    let mut start = low;
+    while start <= high {
+        // Largest block aligned on start that does not exceed high
+        let mut size: u64 = 1;
+        while start.is_multiple_of(size * 2) && start + size * 2 - 1 <= high && size * 2 <= mask {
+            size *= 2;
+        }
+        blocks.push((mask & !(size - 1), start));
+        start += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether one of the blocks matches the argument, as the kernel filter
+    // compares it
+    fn blocked(blocks: &[(u64, u64)], arg: u64) -> bool {
+        blocks.iter().any(|(mask, value)| arg & mask == *value)
+    }
+
+    #[test]
+    fn complement_covers_every_other_value() {
+        let values = [1, 2, 5, 10];
+        let blocks = complement_blocks(&values, SOCK_TYPE_MASK);
+        for arg in 0..=SOCK_TYPE_MASK {
+            assert_eq!(blocked(&blocks, arg), !values.contains(&arg), "type {arg}");
+        }
+    }
+
+    #[test]
+    fn complement_ignores_flags_above_the_mask() {
+        let blocks = complement_blocks(&[libc::SOCK_STREAM as u64], SOCK_TYPE_MASK);
+        let flags = (libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK) as u64;
+        assert!(!blocked(&blocks, libc::SOCK_STREAM as u64 | flags));
+        assert!(blocked(&blocks, libc::SOCK_RAW as u64 | flags));
+    }
+
+    #[test]
+    fn complement_of_int_values_ignores_the_upper_bits() {
+        let values = [libc::SOL_IP as u64, libc::SOL_IPV6 as u64];
+        let blocks = complement_blocks(&values, INT_MASK);
+        for high in [0, 1 << 32, 0xffff_ffff_0000_0000] {
+            for value in values.iter() {
+                assert!(!blocked(&blocks, high | value), "{:#x}", high | value);
+            }
+            for arg in [1, 40, 42, libc::SOL_SOCKET as u64, u32::MAX as u64] {
+                assert!(blocked(&blocks, high | arg), "{:#x}", high | arg);
+            }
+        }
+    }
+
+    #[test]
+    fn complement_of_the_largest_value() {
+        let blocks = complement_blocks(&[INT_MASK], INT_MASK);
+        assert!(!blocked(&blocks, INT_MASK));
+        assert!(!blocked(&blocks, u64::MAX));
+        assert!(blocked(&blocks, 0));
+        assert!(blocked(&blocks, INT_MASK - 1));
+    }
+
+    #[test]
+    fn complement_of_nothing_covers_everything() {
+        let blocks = complement_blocks(&[], INT_MASK);
+        for arg in [0, 1, 1 << 31, INT_MASK, u64::MAX] {
+            assert!(blocked(&blocks, arg), "{:#x}", arg);
+        }
+    }
+
+    #[test]
+    fn blocks_stay_within_the_mask() {
+        let blocks = complement_blocks(&[0, 3, 1000], INT_MASK);
+        assert!(blocks.iter().all(|(mask, value)| mask & !INT_MASK == 0 && value & !mask == 0));
+    }
+
+    // libseccomp refuses overlapping comparisons on one argument and rules
+    // that match the default action, the filter must still build
+    #[test]
+    fn supervised_rules_build_with_either_default() {
+        let socket = 5;
+        for default_action in [ScmpAction::Allow, ScmpAction::Errno(libc::EPERM)] {
+            let mut ctx = ScmpFilterContext::new_filter(default_action).unwrap();
+            for name in ["connect", "sendto", "sendmsg", "sendmmsg"] {
+                let sc = ScmpSyscall::from_name(name).unwrap();
+                for (action, comps) in supervised_rules(name, ScmpAction::Allow, vec![], socket) {
+                    if action != default_action {
+                        add_rule(&mut ctx, action, sc, &comps).unwrap();
+                    }
+                }
+            }
+            if default_action != ScmpAction::Allow {
+                let sendmsg = ScmpSyscall::from(libc::SYS_sendmsg as i32);
+                add_rule(&mut ctx, ScmpAction::Allow, sendmsg, &[ScmpArgCompare::new(0, ScmpCompareOp::Equal, socket as u64)]).unwrap();
+            }
+        }
     }
 }
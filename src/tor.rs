@@ -1,8 +1,9 @@
 use crate::errors::Errcode;
 
 use std::process::{Child, Command, Stdio};
-use std::fs::File;
-use std::io::Write;
+use std::fs::{read, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
 use std::ops::Drop;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -21,9 +22,10 @@ impl TorProcess {
         }
 
         if !data_directory.exists() {
-            std::fs::create_dir_all(data_directory).map_err(|e| {
+            if let Err(e) = std::fs::create_dir_all(data_directory) {
                 log::error!("Can not create tor data directory: {e}");
-            }).unwrap();
+                return Err(Errcode::IoError(format!("Can not create data directory {:?}", data_directory), e));
+            }
         } else if data_directory.is_file() {
             return Err(Errcode::TorError(format!("Tor data dir {:?} exists as file", data_directory)));
         }
@@ -39,8 +41,9 @@ impl TorProcess {
         // Write torrc to file 
         let torrc = data_directory.join("torrc");
         if !torrc.exists() {
-            let mut default_torrc = File::create(&torrc).unwrap();
-            default_torrc.write_all(torrc_contents.as_bytes()).unwrap();
+            if let Err(e) = File::create(&torrc).and_then(|mut file| file.write_all(torrc_contents.as_bytes())) {
+                return Err(Errcode::IoError(format!("Can not write {:?}", torrc), e));
+            }
         }
 
         let process = Command::new(tor_bin_path.as_os_str())
@@ -49,18 +52,58 @@ impl TorProcess {
             .stdin(Stdio::null())
             .arg("-f")
             .arg(torrc)
-            .spawn()
-            .unwrap();
+            // Read by orjailrs list for the bootstrap status
+            .args(["--Log", "notice file /var/lib/tor/notice.log"])
+            // Used by newnym from the host, through the data directory
+            .args(["--ControlSocket", "/var/lib/tor/control.sock"])
+            .args(["--CookieAuthentication", "1"])
+            .spawn();
 
-        Ok(  TorProcess {
-            process,
-        })
+        match process {
+            Ok(process) => Ok(TorProcess { process }),
+            Err(e) => {
+                log::error!("Error while spawning tor: {e}");
+                Err(Errcode::IoError("Error while spawning tor".to_string(), e))
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        let _ = self.process.kill();
     }
 
 }
 
 impl Drop for TorProcess {
     fn drop(&mut self) {
-        let _ = self.process.kill();
+        self.stop();
+    }
+}
+
+// Asks the TOR of a jail for new circuits, tor_data is its data directory
+// seen from the host
+pub fn newnym(tor_data: &Path) -> Result<(), Errcode> {
+    let cookie = match read(tor_data.join("control_auth_cookie")) {
+        Ok(cookie) => cookie,
+        Err(e) => return Err(Errcode::IoError("Can not read the control cookie of TOR".to_string(), e)),
+    };
+    let socket_path = tor_data.join("control.sock");
+    let mut stream = match UnixStream::connect(&socket_path) {
+        Ok(stream) => stream,
+        Err(e) => return Err(Errcode::IoError(format!("Can not connect to {}", socket_path.display()), e)),
+    };
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let cookie: String = cookie.iter().map(|byte| format!("{:02X}", byte)).collect();
+    for command in [format!("AUTHENTICATE {cookie}"), "SIGNAL NEWNYM".to_string()] {
+        let mut reply = String::new();
+        if let Err(e) = stream.write_all(format!("{command}\r\n").as_bytes()).and_then(|_| reader.read_line(&mut reply).map(|_| ())) {
+            return Err(Errcode::IoError("Can not talk to the TOR control socket".to_string(), e));
+        }
+        if !reply.starts_with("250") {
+            return Err(Errcode::TorError(format!("TOR refused {}: {}", command.split(' ').next().unwrap_or_default(), reply.trim_end())));
+        }
     }
+    let _ = stream.write_all(b"QUIT\r\n");
+    Ok(())
 }